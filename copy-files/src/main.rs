@@ -2,9 +2,10 @@ extern crate structopt;
 #[macro_use]
 extern crate structopt_derive;
 
-use anyhow::Result;
-use core::common::Debug;
+use anyhow::{anyhow, Result};
+use core::common::{CollisionStrategy, DateSource, Debug, HashAlgorithm, Preserve, SizeFormat};
 use core::copy_files::{copy_files, CopyFilesConfig};
+use core::path_template::PathTemplate;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -15,7 +16,7 @@ struct CliOpts {
     source_file: String,
 
     #[structopt(short = "o", long = "output", help = "Output folder")]
-    target_folder: String,
+    target_folder: Option<String>,
 
     #[structopt(
         short = "f",
@@ -36,21 +37,144 @@ struct CliOpts {
 
     #[structopt(short = "e", long = "error-log", help = "Error log file.")]
     error_log: Option<String>,
+
+    #[structopt(
+        long = "size-format",
+        help = "Units used to print sizes: si (kB, MB, GB) or iec (KiB, MiB, GiB). Default is si."
+    )]
+    size_format: Option<SizeFormat>,
+
+    #[structopt(
+        long = "preserve",
+        help = "Metadata kept in the copies: timestamps, mode, ownership (only as root), xattrs (including ACLs). Default is timestamps and mode."
+    )]
+    preserve: Vec<Preserve>,
+
+    #[structopt(long = "no-preserve", help = "Don't keep any metadata in the copies.")]
+    no_preserve: bool,
+
+    #[structopt(
+        long = "verify",
+        help = "Hash every copy and compare it with the source, copying it again when they differ. Hashes in the input are used for the sources, so they must be of whole files."
+    )]
+    verify: bool,
+
+    #[structopt(
+        long = "verify-algorithm",
+        help = "Hash algorithm for --verify when the input has no hashes: md5, sha1, sha256, sha512. Default is md5."
+    )]
+    verify_algorithm: Option<HashAlgorithm>,
+
+    #[structopt(
+        long = "verify-retries",
+        help = "How many times a copy that doesn't match its source is made again. Default value is 2."
+    )]
+    verify_retries: Option<u32>,
+
+    #[structopt(
+        long = "resume",
+        help = "Allow an existing output folder and skip the files already copied there with the same size, and the same hash with --verify. Reruns finish interrupted copies."
+    )]
+    resume: bool,
+
+    #[structopt(
+        long = "on-collision",
+        help = "What to do with --flatten-output when a file name is taken: copy-suffix (name - Copy (1).ext), skip-identical (don't copy files with the same content, copy-suffix otherwise), hash-suffix (name-1a2b3c4d.ext) or parent-folder (name (folder).ext). Default is copy-suffix."
+    )]
+    collisions: Option<CollisionStrategy>,
+
+    #[structopt(
+        short = "t",
+        long = "template",
+        help = "Layout of the output folder, like {year}/{month}/{stem}{ext} or {hash:2}/{hash}. Variables: year, month, day (of the modification date), name, stem, ext (with the dot), extension (without it), hash, hash:N (first N characters), parent (source folder name), path (whole source path). Replaces --flatten-output."
+    )]
+    template: Option<PathTemplate>,
+
+    #[structopt(
+        long = "strip-prefix",
+        help = "Remove this folder from the start of the source paths when recreating the tree or in {path}."
+    )]
+    strip_prefix: Option<String>,
+
+    #[structopt(
+        long = "date-source",
+        help = "Date used by {year}, {month} and {day} in --template: modified, or exif for when photos were taken, falling back to the modification date. Default is modified."
+    )]
+    date_source: Option<DateSource>,
+
+    #[structopt(
+        long = "no-exif-log",
+        help = "File listing the photos without EXIF date with --date-source exif."
+    )]
+    no_exif_log: Option<String>,
+
+    #[structopt(
+        long = "move",
+        help = "Move the files instead of copying them. They are renamed when in the same filesystem, otherwise copied, verified and deleted. Needs --journal."
+    )]
+    move_files: bool,
+
+    #[structopt(
+        long = "journal",
+        help = "CSV file where --move writes every moved file, to undo the moves later with --undo."
+    )]
+    journal: Option<String>,
+
+    #[structopt(
+        long = "undo",
+        help = "Move back the files of the journal given as input, no output folder is needed."
+    )]
+    undo: bool,
+
+    #[structopt(
+        long = "store",
+        help = "Use the output folder as a content-addressed store: every file is kept once in objects/ab/cdef... by its hash, and index.csv lists the original paths. Files are hashed whole, with the algorithm of the input hashes (md5 when there are none). Can't be combined with --move, --undo, --resume, --flatten-output, --template or --strip-prefix."
+    )]
+    store: bool,
 }
 
 impl CliOpts {
-    fn into_config(self) -> CopyFilesConfig {
-        CopyFilesConfig {
+    fn into_config(self) -> Result<CopyFilesConfig> {
+        let target_folder = match (&self.target_folder, self.undo) {
+            (Some(target_folder), _) => PathBuf::from(target_folder),
+            (None, true) => PathBuf::new(),
+            (None, false) => return Err(anyhow!("The output folder is required")),
+        };
+        Ok(CopyFilesConfig {
             source_file: PathBuf::from(&self.source_file),
-            target_folder: PathBuf::from(&self.target_folder),
+            target_folder,
             flatten_output: self.flatten_output,
             show_progression: self.progression,
             debug: if self.debug { Debug::On } else { Debug::Off },
             error_log: self.error_log.as_ref().map(|path| PathBuf::from(&path)),
-        }
+            size_format: self.size_format.unwrap_or_default(),
+            preserve: if self.no_preserve {
+                vec![]
+            } else if self.preserve.is_empty() {
+                vec![Preserve::Timestamps, Preserve::Mode]
+            } else {
+                self.preserve
+            },
+            verify: if self.verify {
+                Some(self.verify_algorithm.unwrap_or(HashAlgorithm::Md5))
+            } else {
+                None
+            },
+            verify_retries: self.verify_retries.unwrap_or(2),
+            resume: self.resume,
+            collisions: self.collisions.unwrap_or_default(),
+            template: self.template,
+            strip_prefix: self.strip_prefix.as_ref().map(PathBuf::from),
+            date_source: self.date_source.unwrap_or_default(),
+            no_exif_log: self.no_exif_log.as_ref().map(PathBuf::from),
+            move_files: self.move_files,
+            journal: self.journal.as_ref().map(PathBuf::from),
+            undo: self.undo,
+            store: self.store,
+        })
     }
 }
 
 fn main() -> Result<()> {
-    copy_files(CliOpts::from_args().into_config())
+    copy_files(CliOpts::from_args().into_config()?)
 }
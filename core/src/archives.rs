@@ -0,0 +1,606 @@
+use crate::iso9660::Image;
+use anyhow::{anyhow, Result};
+use chrono::NaiveDate;
+use flate2::read::GzDecoder;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// Files inside archives are addressed with virtual paths like
+// "/backup/a.zip!/photos/img.jpg".
+const SEPARATOR: &[u8] = b"!/";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+    SevenZ,
+    Iso,
+}
+
+impl ArchiveKind {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        Self::from_name(path.file_name()?.as_encoded_bytes())
+    }
+
+    fn from_name(name: &[u8]) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        if name.ends_with(b".zip") {
+            Some(Self::Zip)
+        } else if name.ends_with(b".tar") {
+            Some(Self::Tar)
+        } else if name.ends_with(b".tar.gz") || name.ends_with(b".tgz") {
+            Some(Self::TarGz)
+        } else if name.ends_with(b".7z") {
+            Some(Self::SevenZ)
+        } else if name.ends_with(b".iso") {
+            Some(Self::Iso)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Member {
+    pub name: Vec<u8>,
+    pub size: u64,
+    pub modified: Option<i64>,
+}
+
+#[derive(Clone)]
+pub struct VirtualPath {
+    pub archive: PathBuf,
+    pub kind: ArchiveKind,
+    pub member: Vec<u8>,
+}
+
+pub fn virtual_path(archive: &Path, member: &[u8]) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(OsStr::new("!/"));
+    path.push(os_string_from_bytes(member.to_vec()));
+    PathBuf::from(path)
+}
+
+pub fn split_virtual_path(path: &Path) -> Option<VirtualPath> {
+    let bytes = path.as_os_str().as_encoded_bytes();
+    let mut start = 0;
+    while let Some(position) = find(&bytes[start..], SEPARATOR) {
+        let end = start + position;
+        if let Some(kind) = ArchiveKind::from_name(&bytes[..end]) {
+            // Splitting right before an ASCII character keeps both halves valid.
+            let archive = unsafe { OsStr::from_encoded_bytes_unchecked(&bytes[..end]) };
+            return Some(VirtualPath {
+                archive: PathBuf::from(archive),
+                kind,
+                member: bytes[end + SEPARATOR.len()..].to_vec(),
+            });
+        }
+        start = end + SEPARATOR.len();
+    }
+    None
+}
+
+pub fn list_members(path: &Path, kind: ArchiveKind) -> Result<Vec<Member>> {
+    match kind {
+        ArchiveKind::Zip => list_zip(path),
+        ArchiveKind::Tar => list_tar(File::open(path)?),
+        ArchiveKind::TarGz => list_tar(GzDecoder::new(File::open(path)?)),
+        ArchiveKind::SevenZ => list_7z(path),
+        ArchiveKind::Iso => list_iso(path),
+    }
+}
+
+// Reads the given members going through the archive only once, as reaching
+// a member of a tar or 7z file means decompressing everything before it.
+// f gets the position of each member in the list along with its reader, or
+// with the error when only that member couldn't be read, like encrypted ones.
+// Members come in archive order and the missing ones at the end. Errors
+// returned by f stop the reading. The names must be unique.
+pub fn for_each_member(
+    archive: &Path,
+    kind: ArchiveKind,
+    members: &[&[u8]],
+    mut f: impl FnMut(usize, Result<&mut dyn Read>) -> Result<()>,
+) -> Result<()> {
+    let mut pending: HashMap<&[u8], usize> = members
+        .iter()
+        .enumerate()
+        .map(|(index, member)| (*member, index))
+        .collect();
+    match kind {
+        ArchiveKind::Zip => read_zip(archive, &mut pending, &mut f)?,
+        ArchiveKind::Tar => read_tar(File::open(archive)?, &mut pending, &mut f)?,
+        ArchiveKind::TarGz => read_tar(GzDecoder::new(File::open(archive)?), &mut pending, &mut f)?,
+        ArchiveKind::SevenZ => read_7z(archive, &mut pending, &mut f)?,
+        ArchiveKind::Iso => read_iso(archive, &mut pending, &mut f)?,
+    }
+    let mut missing: Vec<usize> = pending.into_values().collect();
+    missing.sort_unstable();
+    for index in missing {
+        f(index, Err(not_found(archive, members[index])))?;
+    }
+    Ok(())
+}
+
+pub fn with_member<T>(
+    virtual_path: &VirtualPath,
+    f: impl FnOnce(&mut dyn Read) -> Result<T>,
+) -> Result<T> {
+    let mut f = Some(f);
+    let mut result = None;
+    for_each_member(
+        &virtual_path.archive,
+        virtual_path.kind,
+        &[&virtual_path.member],
+        |_, reader| {
+            if let Some(f) = f.take() {
+                result = Some(reader.and_then(f));
+            }
+            Ok(())
+        },
+    )?;
+    result.unwrap_or_else(|| Err(not_found(&virtual_path.archive, &virtual_path.member)))
+}
+
+// Consecutive members of one archive gathered along with some item each, so
+// they are read in a single pass instead of once per member.
+pub struct MemberBatch<T> {
+    archive: Option<(PathBuf, ArchiveKind)>,
+    names: HashSet<Vec<u8>>,
+    items: Vec<(Vec<u8>, T)>,
+}
+
+impl<T> Default for MemberBatch<T> {
+    fn default() -> Self {
+        MemberBatch {
+            archive: None,
+            names: HashSet::new(),
+            items: Vec::new(),
+        }
+    }
+}
+
+impl<T> MemberBatch<T> {
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    // Members of other archives, or read already, need a batch of their own.
+    pub fn accepts(&self, member: &VirtualPath) -> bool {
+        match &self.archive {
+            Some((archive, _)) => {
+                *archive == member.archive && !self.names.contains(&member.member)
+            }
+            None => true,
+        }
+    }
+
+    pub fn push(&mut self, member: VirtualPath, item: T) {
+        if self.archive.is_none() {
+            self.archive = Some((member.archive, member.kind));
+        }
+        self.names.insert(member.member.clone());
+        self.items.push((member.member, item));
+    }
+
+    // Reads the members, handing each item to f along with the reader of its
+    // member. The batch is left empty, and the items come back in the order
+    // they were pushed, with the result of f or the reason their member
+    // couldn't be read.
+    pub fn read<R>(
+        &mut self,
+        mut f: impl FnMut(&mut T, &mut dyn Read) -> Result<R>,
+    ) -> Vec<(T, Result<R>)> {
+        let (archive, kind) = match self.archive.take() {
+            Some(archive) => archive,
+            None => return Vec::new(),
+        };
+        self.names.clear();
+        let (names, mut items): (Vec<Vec<u8>>, Vec<T>) = self.items.drain(..).unzip();
+        let members: Vec<&[u8]> = names.iter().map(|name| &name[..]).collect();
+        let mut results: Vec<Option<Result<R>>> = items.iter().map(|_| None).collect();
+        let read = for_each_member(&archive, kind, &members, |index, reader| {
+            results[index] = Some(reader.and_then(|reader| f(&mut items[index], reader)));
+            Ok(())
+        });
+        items
+            .into_iter()
+            .zip(results)
+            .map(|(item, result)| {
+                // Members left behind by an archive that couldn't be read get its error.
+                let result = result.unwrap_or_else(|| match &read {
+                    Ok(()) => Err(anyhow!("Member not read from archive {:?}.", archive)),
+                    Err(e) => Err(anyhow!("Couldn't read archive {:?}: {:#}", archive, e)),
+                });
+                (item, result)
+            })
+            .collect()
+    }
+}
+
+type Pending<'a> = HashMap<&'a [u8], usize>;
+type MemberFn<'a> = dyn FnMut(usize, Result<&mut dyn Read>) -> Result<()> + 'a;
+
+fn list_zip(path: &Path) -> Result<Vec<Member>> {
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let mut members = Vec::with_capacity(archive.len());
+    for i in 0..archive.len() {
+        let file = archive.by_index_raw(i)?;
+        if !file.is_file() {
+            continue;
+        }
+        members.push(Member {
+            name: normalize_name(file.name().as_bytes()).to_vec(),
+            size: file.size(),
+            modified: file.last_modified().and_then(|date| {
+                NaiveDate::from_ymd_opt(date.year().into(), date.month().into(), date.day().into())?
+                    .and_hms_opt(
+                        date.hour().into(),
+                        date.minute().into(),
+                        date.second().into(),
+                    )
+                    .map(|date| date.and_utc().timestamp())
+            }),
+        });
+    }
+    Ok(members)
+}
+
+fn read_zip(path: &Path, pending: &mut Pending, f: &mut MemberFn) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    for i in 0..archive.len() {
+        if pending.is_empty() {
+            break;
+        }
+        let index = {
+            let file = archive.by_index_raw(i)?;
+            if !file.is_file() {
+                continue;
+            }
+            match pending.remove(normalize_name(file.name().as_bytes())) {
+                Some(index) => index,
+                None => continue,
+            }
+        };
+        match archive.by_index(i) {
+            Ok(mut file) => f(index, Ok(&mut file))?,
+            Err(e) => f(index, Err(e.into()))?,
+        }
+    }
+    Ok(())
+}
+
+fn list_tar(reader: impl Read) -> Result<Vec<Member>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut members = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        members.push(Member {
+            name: normalize_name(&entry.path_bytes()).to_vec(),
+            size: entry.size(),
+            modified: entry.header().mtime().ok().map(|mtime| mtime as i64),
+        });
+    }
+    Ok(members)
+}
+
+fn read_tar(reader: impl Read, pending: &mut Pending, f: &mut MemberFn) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        if pending.is_empty() {
+            break;
+        }
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let index = pending.remove(normalize_name(&entry.path_bytes()));
+        if let Some(index) = index {
+            f(index, Ok(&mut entry))?;
+        }
+    }
+    Ok(())
+}
+
+fn list_7z(path: &Path) -> Result<Vec<Member>> {
+    let archive = sevenz_rust::Archive::open(path)?;
+    Ok(archive
+        .files
+        .iter()
+        .filter(|entry| !entry.is_directory() && !entry.is_anti_item())
+        .map(|entry| Member {
+            name: normalize_name(entry.name().as_bytes()).to_vec(),
+            size: entry.size(),
+            modified: Some(nt_time_to_unix(entry.last_modified_date().to_raw())),
+        })
+        .collect())
+}
+
+fn read_7z(path: &Path, pending: &mut Pending, f: &mut MemberFn) -> Result<()> {
+    let mut reader = sevenz_rust::SevenZReader::open(path, sevenz_rust::Password::empty())?;
+    let mut result = Ok(());
+    reader.for_each_entries(|entry, entry_reader| {
+        if !entry.is_directory() && !entry.is_anti_item() {
+            if let Some(index) = pending.remove(normalize_name(entry.name().as_bytes())) {
+                result = f(index, Ok(entry_reader));
+            }
+        }
+        if result.is_err() || pending.is_empty() {
+            return Ok(false);
+        }
+        // Members of solid archives share one stream, so whatever wasn't read
+        // has to be skipped to reach the next one.
+        std::io::copy(entry_reader, &mut std::io::sink())?;
+        Ok(true)
+    })?;
+    result
+}
+
+fn list_iso(path: &Path) -> Result<Vec<Member>> {
+    Ok(Image::open(path)?
+        .files()?
+        .into_iter()
+        .map(|(name, entry)| Member {
+            name,
+            size: entry.size,
+            modified: entry.modified,
+        })
+        .collect())
+}
+
+fn read_iso(path: &Path, pending: &mut Pending, f: &mut MemberFn) -> Result<()> {
+    let mut image = Image::open(path)?;
+    let mut members: Vec<(&[u8], usize)> = pending.drain().collect();
+    members.sort_unstable_by_key(|(_, index)| *index);
+    for (member, index) in members {
+        match image.find(member) {
+            Ok(entry) => match image.reader(&entry) {
+                Ok(mut reader) => f(index, Ok(&mut reader))?,
+                Err(e) => f(index, Err(e))?,
+            },
+            Err(e) => f(index, Err(e))?,
+        }
+    }
+    Ok(())
+}
+
+fn not_found(path: &Path, member: &[u8]) -> anyhow::Error {
+    anyhow!(
+        "Member {:?} not found in archive {:?}.",
+        String::from_utf8_lossy(member),
+        path
+    )
+}
+
+fn normalize_name(mut name: &[u8]) -> &[u8] {
+    loop {
+        if name.starts_with(b"./") {
+            name = &name[2..];
+        } else if name.starts_with(b"/") {
+            name = &name[1..];
+        } else {
+            return name;
+        }
+    }
+}
+
+fn nt_time_to_unix(raw: u64) -> i64 {
+    (raw / 10_000_000) as i64 - 11_644_473_600
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(unix)]
+fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    OsString::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_virtual_path() {
+        let actual =
+            split_virtual_path(Path::new("/backup/Wow!/a.tar.gz!/photos/img.jpg")).unwrap();
+        assert_eq!(actual.archive, Path::new("/backup/Wow!/a.tar.gz"));
+        assert_eq!(actual.kind, ArchiveKind::TarGz);
+        assert_eq!(actual.member, b"photos/img.jpg");
+    }
+
+    #[test]
+    fn test_split_regular_path() {
+        assert!(split_virtual_path(Path::new("/backup/Wow!/img.jpg")).is_none());
+    }
+
+    #[test]
+    fn test_virtual_path_roundtrip() {
+        let path = virtual_path(Path::new("/backup/A.ZIP"), b"photos/img.jpg");
+        assert_eq!(path, Path::new("/backup/A.ZIP!/photos/img.jpg"));
+        let actual = split_virtual_path(&path).unwrap();
+        assert_eq!(actual.archive, Path::new("/backup/A.ZIP"));
+        assert_eq!(actual.kind, ArchiveKind::Zip);
+        assert_eq!(actual.member, b"photos/img.jpg");
+    }
+
+    const FILES: &[(&str, &str)] = &[
+        ("a.txt", "first"),
+        ("dir/b.txt", "second"),
+        ("c.txt", "third"),
+    ];
+
+    // Reads the given members and returns what each call got, in order.
+    fn read(path: &Path, kind: ArchiveKind, members: &[&str]) -> Vec<(String, String)> {
+        let names: Vec<&[u8]> = members.iter().map(|member| member.as_bytes()).collect();
+        let mut calls = Vec::new();
+        for_each_member(path, kind, &names, |index, reader| {
+            let mut content = String::new();
+            let content = match reader.and_then(|reader| Ok(reader.read_to_string(&mut content)?)) {
+                Ok(_) => content,
+                Err(_) => "ERROR".into(),
+            };
+            calls.push((members[index].to_string(), content));
+            Ok(())
+        })
+        .unwrap();
+        calls
+    }
+
+    fn calls(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|(name, content)| (name.to_string(), content.to_string()))
+            .collect()
+    }
+
+    fn write_zip(path: &Path) {
+        let mut writer = zip::ZipWriter::new(File::create(path).unwrap());
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        for (name, content) in FILES {
+            writer.start_file(*name, options).unwrap();
+            std::io::Write::write_all(&mut writer, content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    fn write_tar(writer: impl std::io::Write) {
+        let mut builder = tar::Builder::new(writer);
+        for (name, content) in FILES {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, content.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap();
+    }
+
+    #[test]
+    fn test_read_zip_members_in_one_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.zip");
+        write_zip(&path);
+        assert_eq!(
+            read(&path, ArchiveKind::Zip, &["missing", "c.txt", "a.txt"]),
+            calls(&[("a.txt", "first"), ("c.txt", "third"), ("missing", "ERROR")])
+        );
+    }
+
+    #[test]
+    fn test_encrypted_zip_members_are_reported_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.zip");
+        write_zip(&path);
+        // Sets the encryption flag of dir/b.txt in its local and central headers.
+        let mut bytes = std::fs::read(&path).unwrap();
+        let name: &[u8] = b"dir/b.txt";
+        let local = find(&bytes, name).unwrap() - 30;
+        let central = bytes
+            .windows(name.len())
+            .rposition(|window| window == name)
+            .unwrap()
+            - 46;
+        bytes[local + 6] |= 1;
+        bytes[central + 8] |= 1;
+        std::fs::write(&path, bytes).unwrap();
+        assert_eq!(
+            read(&path, ArchiveKind::Zip, &["a.txt", "dir/b.txt", "c.txt"]),
+            calls(&[
+                ("a.txt", "first"),
+                ("dir/b.txt", "ERROR"),
+                ("c.txt", "third")
+            ])
+        );
+    }
+
+    #[test]
+    fn test_read_tar_members_in_one_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.tar");
+        write_tar(File::create(&path).unwrap());
+        assert_eq!(
+            read(&path, ArchiveKind::Tar, &["c.txt", "dir/b.txt", "missing"]),
+            calls(&[
+                ("dir/b.txt", "second"),
+                ("c.txt", "third"),
+                ("missing", "ERROR")
+            ])
+        );
+    }
+
+    #[test]
+    fn test_read_tar_gz_members_in_one_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.tar.gz");
+        write_tar(flate2::write::GzEncoder::new(
+            File::create(&path).unwrap(),
+            flate2::Compression::default(),
+        ));
+        assert_eq!(
+            read(&path, ArchiveKind::TarGz, &["a.txt", "c.txt"]),
+            calls(&[("a.txt", "first"), ("c.txt", "third")])
+        );
+    }
+
+    #[test]
+    fn test_read_solid_7z_members_in_one_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.7z");
+        let mut writer = sevenz_rust::SevenZWriter::create(&path).unwrap();
+        let entries = FILES
+            .iter()
+            .map(|(name, _)| {
+                let mut entry = sevenz_rust::SevenZArchiveEntry::new();
+                entry.name = name.to_string();
+                entry.has_stream = true;
+                entry
+            })
+            .collect();
+        let readers = FILES
+            .iter()
+            .map(|(_, content)| sevenz_rust::SourceReader::from(content.as_bytes()))
+            .collect();
+        writer
+            .push_archive_entries(entries, sevenz_rust::SeqReader::new(readers))
+            .unwrap();
+        writer.finish().unwrap();
+        assert_eq!(
+            read(
+                &path,
+                ArchiveKind::SevenZ,
+                &["c.txt", "missing", "dir/b.txt"]
+            ),
+            calls(&[
+                ("dir/b.txt", "second"),
+                ("c.txt", "third"),
+                ("missing", "ERROR")
+            ])
+        );
+        let virtual_path = split_virtual_path(&virtual_path(&path, b"c.txt")).unwrap();
+        let mut content = String::new();
+        with_member(&virtual_path, |reader| {
+            Ok(reader.read_to_string(&mut content)?)
+        })
+        .unwrap();
+        assert_eq!(content, "third");
+    }
+}
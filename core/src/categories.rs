@@ -0,0 +1,149 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+// Built-in categories of files, as lowercase extensions without the dot.
+const PRESETS: &[(&str, &[&str])] = &[
+    (
+        "pictures",
+        &[
+            "jpg", "jpeg", "jpe", "png", "gif", "bmp", "webp", "tif", "tiff", "svg", "heic",
+            "heif", "avif", "ico", "jxl",
+        ],
+    ),
+    (
+        "raw-photos",
+        &[
+            "raw", "dng", "cr2", "cr3", "crw", "nef", "nrw", "arw", "srf", "sr2", "orf", "rw2",
+            "raf", "pef", "srw", "x3f", "3fr", "erf", "kdc", "mrw", "rwl",
+        ],
+    ),
+    (
+        "music",
+        &[
+            "mp3", "ogg", "oga", "opus", "flac", "wav", "m4a", "aac", "wma", "aiff", "aif", "alac",
+            "ape", "mid", "midi",
+        ],
+    ),
+    // "ts" is left to source-code, as TypeScript files outnumber MPEG
+    // transport streams by far. A categories file can add it back.
+    (
+        "video",
+        &[
+            "mp4", "m4v", "mov", "avi", "mkv", "webm", "wmv", "flv", "mpg", "mpeg", "m2ts", "mts",
+            "3gp", "vob", "ogv",
+        ],
+    ),
+    (
+        "documents",
+        &[
+            "pdf", "doc", "docx", "odt", "rtf", "txt", "md", "xls", "xlsx", "ods", "csv", "ppt",
+            "pptx", "odp", "epub", "djvu", "ps", "tex",
+        ],
+    ),
+    (
+        "source-code",
+        &[
+            "rs", "c", "h", "cc", "cpp", "hpp", "cs", "java", "kt", "scala", "go", "py", "rb",
+            "php", "js", "jsx", "ts", "tsx", "html", "css", "scss", "sh", "bat", "ps1", "pl",
+            "lua", "swift", "hs", "ml", "sql", "asm",
+        ],
+    ),
+    (
+        "archives",
+        &[
+            "zip", "7z", "rar", "tar", "gz", "tgz", "bz2", "tbz2", "xz", "txz", "zst", "lz",
+            "lzma", "cab", "arj",
+        ],
+    ),
+    (
+        "disk-images",
+        &[
+            "iso", "img", "bin", "cue", "nrg", "mdf", "mds", "dmg", "vhd", "vhdx", "vmdk", "qcow2",
+            "vdi",
+        ],
+    ),
+];
+
+// Returns the extensions of all the given categories. The categories file is
+// a JSON object of category names to extensions, like {"comics": ["cbz"]}, and
+// its categories replace the built-in ones with the same name.
+pub fn category_extensions(
+    names: &[String],
+    categories_file: Option<&Path>,
+) -> Result<Vec<String>> {
+    let mut categories: BTreeMap<String, Vec<String>> = PRESETS
+        .iter()
+        .map(|(name, extensions)| {
+            (
+                name.to_string(),
+                extensions.iter().map(|ext| ext.to_string()).collect(),
+            )
+        })
+        .collect();
+    if let Some(path) = categories_file {
+        let user_categories: BTreeMap<String, Vec<String>> =
+            serde_json::from_reader(File::open(path)?)
+                .map_err(|e| anyhow!("Wrong categories file {:?}: {}", path, e))?;
+        categories.extend(user_categories);
+    }
+    let mut extensions = Vec::new();
+    for name in names {
+        match categories.get(name) {
+            Some(category) => extensions.extend(
+                category
+                    .iter()
+                    .map(|ext| ext.trim_start_matches('.').to_lowercase()),
+            ),
+            None => {
+                return Err(anyhow!(
+                    "No category named '{}', try these instead: {}.",
+                    name,
+                    categories.keys().cloned().collect::<Vec<_>>().join(", ")
+                ))
+            }
+        }
+    }
+    Ok(extensions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_presets_are_combined() {
+        let actual = category_extensions(&["pictures".into(), "raw-photos".into()], None).unwrap();
+        assert!(actual.contains(&"jpg".to_string()));
+        assert!(actual.contains(&"cr2".to_string()));
+        assert!(!actual.contains(&"mp3".to_string()));
+    }
+
+    #[test]
+    fn test_unknown_category_lists_the_available_ones() {
+        let actual = category_extensions(&["comics".into()], None).unwrap_err();
+        assert!(actual.to_string().contains("disk-images"));
+    }
+
+    #[test]
+    fn test_presets_are_lowercase_without_dot() {
+        for (_, extensions) in PRESETS {
+            for ext in extensions.iter() {
+                assert_eq!(*ext, ext.trim_start_matches('.').to_lowercase());
+            }
+        }
+    }
+
+    #[test]
+    fn test_presets_do_not_overlap() {
+        let mut seen = std::collections::HashMap::new();
+        for (name, extensions) in PRESETS {
+            for ext in extensions.iter() {
+                if let Some(other) = seen.insert(*ext, *name) {
+                    panic!("{} is in both {} and {}", ext, other, name);
+                }
+            }
+        }
+    }
+}
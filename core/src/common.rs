@@ -4,6 +4,28 @@ pub enum TraverseMode {
     NonRecursive,
 }
 
+#[derive(Copy, Clone, Debug)]
+pub enum SortOrder {
+    ByteWise,
+    Natural,
+    CaseInsensitive,
+}
+
+impl std::str::FromStr for SortOrder {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bytewise" => Ok(Self::ByteWise),
+            "natural" => Ok(Self::Natural),
+            "case-insensitive" => Ok(Self::CaseInsensitive),
+            _ => Err(format!(
+                "No sort order named '{}', try these instead: bytewise, natural, case-insensitive.",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum Debug {
     On,
@@ -42,6 +64,19 @@ pub enum HashAlgorithm {
     Sha512,
 }
 
+impl HashAlgorithm {
+    // Tells which algorithm produced a hex encoded hash by its length.
+    pub fn of_hash(hash: &str) -> Option<Self> {
+        match hash.len() {
+            32 => Some(Self::Md5),
+            40 => Some(Self::Sha1),
+            64 => Some(Self::Sha256),
+            128 => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+}
+
 impl std::str::FromStr for HashAlgorithm {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -57,3 +92,287 @@ impl std::str::FromStr for HashAlgorithm {
         }
     }
 }
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FileCategory {
+    Image,
+    Audio,
+    Video,
+    Document,
+    Archive,
+}
+
+impl std::str::FromStr for FileCategory {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "image" => Ok(Self::Image),
+            "audio" => Ok(Self::Audio),
+            "video" => Ok(Self::Video),
+            "document" => Ok(Self::Document),
+            "archive" => Ok(Self::Archive),
+            _ => Err(format!(
+                "No file type named '{}', try these instead: image, audio, video, document, archive.",
+                s
+            )),
+        }
+    }
+}
+
+// Point in time as unix seconds, given either as a local date like 2019-06-15,
+// a local date and time like "2019-06-15 18:30" or 2019-06-15T18:30:00, or
+// relative to now like 12h, 30d, 2w or 1y.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Timestamp(pub i64);
+
+impl std::str::FromStr for Timestamp {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+        let s = s.trim();
+        let error = || {
+            format!(
+                "Wrong date '{}', try a date like 2019-06-15, \"2019-06-15 18:30\" or a time ago like 30d.",
+                s
+            )
+        };
+        if let Some(unit) = s.chars().last().filter(|c| c.is_ascii_alphabetic()) {
+            let amount: i64 = s[..s.len() - 1].parse().map_err(|_| error())?;
+            let seconds = match unit {
+                'h' => 60 * 60,
+                'd' => 24 * 60 * 60,
+                'w' => 7 * 24 * 60 * 60,
+                'y' => 365 * 24 * 60 * 60,
+                _ => return Err(error()),
+            };
+            return Ok(Timestamp(Local::now().timestamp() - amount * seconds));
+        }
+        let date_time = [
+            "%Y-%m-%d %H:%M:%S",
+            "%Y-%m-%dT%H:%M:%S",
+            "%Y-%m-%d %H:%M",
+            "%Y-%m-%dT%H:%M",
+        ]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(error)?;
+        Local
+            .from_local_datetime(&date_time)
+            .earliest()
+            .map(|date_time| Timestamp(date_time.timestamp()))
+            .ok_or_else(error)
+    }
+}
+
+// Amount of bytes given as a plain number or with a SI (KB, MB, GB, TB) or
+// IEC (KiB, MiB, GiB, TiB) unit, like 10MB or 1.5GiB.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ByteSize(pub u64);
+
+impl std::str::FromStr for ByteSize {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number: f64 = number
+            .parse()
+            .map_err(|_| format!("Wrong size '{}', try something like 10MB or 1.5GiB.", s))?;
+        let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+            "" | "b" => 1,
+            "kb" | "k" => 1_000,
+            "mb" | "m" => 1_000_000,
+            "gb" | "g" => 1_000_000_000,
+            "tb" | "t" => 1_000_000_000_000,
+            "kib" => 1 << 10,
+            "mib" => 1 << 20,
+            "gib" => 1 << 30,
+            "tib" => 1 << 40,
+            _ => {
+                return Err(format!(
+                    "Wrong size unit in '{}', try these instead: B, KB, MB, GB, TB, KiB, MiB, GiB, TiB.",
+                    s
+                ))
+            }
+        };
+        Ok(ByteSize((number * multiplier as f64).round() as u64))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub enum SizeFormat {
+    #[default]
+    Si,
+    Iec,
+}
+
+impl SizeFormat {
+    pub fn format(self, size: u64) -> String {
+        match self {
+            Self::Si => format!("{}B", size_format::SizeFormatterSI::new(size)),
+            Self::Iec => format!("{}B", size_format::SizeFormatterBinary::new(size)),
+        }
+    }
+}
+
+impl std::str::FromStr for SizeFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "si" => Ok(Self::Si),
+            "iec" => Ok(Self::Iec),
+            _ => Err(format!(
+                "No size format named '{}', try these instead: si, iec.",
+                s
+            )),
+        }
+    }
+}
+
+// What makes records belong to the same group when looking for unique or
+// duplicated records.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GroupKey {
+    Size,
+    Hash,
+    Basename,
+    BasenameCaseInsensitive,
+    Stem,
+    Extension,
+    SizeAndBasename,
+}
+
+impl GroupKey {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Size => "size",
+            Self::Hash => "hash",
+            Self::Basename => "basename",
+            Self::BasenameCaseInsensitive => "basename-ci",
+            Self::Stem => "stem",
+            Self::Extension => "extension",
+            Self::SizeAndBasename => "size-basename",
+        }
+    }
+}
+
+impl std::str::FromStr for GroupKey {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "size" => Ok(Self::Size),
+            "hash" => Ok(Self::Hash),
+            "basename" => Ok(Self::Basename),
+            "basename-ci" => Ok(Self::BasenameCaseInsensitive),
+            "stem" => Ok(Self::Stem),
+            "extension" => Ok(Self::Extension),
+            "size-basename" => Ok(Self::SizeAndBasename),
+            _ => Err(format!(
+                "No group key named '{}', try these instead: size, hash, basename, basename-ci, stem, extension, size-basename.",
+                s
+            )),
+        }
+    }
+}
+
+// Date used by the copy-files templates: when the file was modified, or
+// when the photo was taken according to its EXIF data.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum DateSource {
+    #[default]
+    Modified,
+    Exif,
+}
+
+impl std::str::FromStr for DateSource {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "modified" => Ok(Self::Modified),
+            "exif" => Ok(Self::Exif),
+            _ => Err(format!(
+                "No date source named '{}', try these instead: modified, exif.",
+                s
+            )),
+        }
+    }
+}
+
+// File metadata that copy-files carries over to the copies.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Preserve {
+    Timestamps,
+    Mode,
+    Ownership,
+    Xattrs,
+}
+
+impl std::str::FromStr for Preserve {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "timestamps" => Ok(Self::Timestamps),
+            "mode" => Ok(Self::Mode),
+            "ownership" => Ok(Self::Ownership),
+            "xattrs" => Ok(Self::Xattrs),
+            _ => Err(format!(
+                "Can't preserve '{}', try these instead: timestamps, mode, ownership, xattrs.",
+                s
+            )),
+        }
+    }
+}
+
+// How copy-files names a file when flattening the output and another file
+// already has its name.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum CollisionStrategy {
+    #[default]
+    CopySuffix,
+    SkipIdentical,
+    HashSuffix,
+    ParentFolder,
+}
+
+impl std::str::FromStr for CollisionStrategy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "copy-suffix" => Ok(Self::CopySuffix),
+            "skip-identical" => Ok(Self::SkipIdentical),
+            "hash-suffix" => Ok(Self::HashSuffix),
+            "parent-folder" => Ok(Self::ParentFolder),
+            _ => Err(format!(
+                "No collision strategy named '{}', try these instead: copy-suffix, skip-identical, hash-suffix, parent-folder.",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_byte_size_units() {
+        assert_eq!("10485760".parse(), Ok(ByteSize(10_485_760)));
+        assert_eq!("10MiB".parse(), Ok(ByteSize(10_485_760)));
+        assert_eq!("10MB".parse(), Ok(ByteSize(10_000_000)));
+        assert_eq!("1.5 GiB".parse(), Ok(ByteSize(1_610_612_736)));
+        assert!("10XB".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn test_size_format() {
+        assert_eq!(SizeFormat::Si.format(1_500), "1.5kB");
+        assert_eq!(SizeFormat::Iec.format(1_536), "1.5KiB");
+    }
+}
@@ -1,12 +1,19 @@
-use crate::common::Debug;
-use crate::internals::{Record, Reporter};
+use crate::archives::MemberBatch;
+use crate::common::{CollisionStrategy, DateSource, Debug, HashAlgorithm, Preserve, SizeFormat};
+use crate::exif_date::exif_date;
+use crate::internals::{
+    compute_hash, copy_source, decode_path, encode_path, modified_time, record_hash, with_source,
+    Record, Reporter, Source,
+};
+use crate::path_template::{PathTemplate, TemplateSource};
 use anyhow::{anyhow, Result};
 use num_format::{Locale, ToFormattedString};
 use regex::Regex;
-use size_format::SizeFormatterSI;
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::ffi::OsString;
-use std::fs::File;
+use std::fs::{File, FileTimes, Metadata, OpenOptions};
+use std::io::BufRead;
 use std::path::{Path, PathBuf};
 use std::time::Instant;
 
@@ -18,23 +25,97 @@ pub struct CopyFilesConfig {
     pub debug: Debug,
     pub flatten_output: bool,
     pub error_log: Option<PathBuf>,
+    pub size_format: SizeFormat,
+    pub preserve: Vec<Preserve>,
+    pub verify: Option<HashAlgorithm>,
+    pub verify_retries: u32,
+    pub resume: bool,
+    pub collisions: CollisionStrategy,
+    pub template: Option<PathTemplate>,
+    pub strip_prefix: Option<PathBuf>,
+    pub date_source: DateSource,
+    pub no_exif_log: Option<PathBuf>,
+    pub move_files: bool,
+    pub journal: Option<PathBuf>,
+    // Moves back the files of the journal given as source file.
+    pub undo: bool,
+    pub store: bool,
+}
+
+// Layout of a content-addressed store: files are kept once in objects/ by
+// their hash, and the index lists the original path of every stored file.
+pub(crate) const STORE_INDEX: &str = "index.csv";
+pub(crate) const STORE_OBJECTS: &str = "objects";
+
+// Room taken at most by the archive members extracted to be copied, unless
+// a single one is bigger.
+const MEMBER_BATCH_SIZE: u64 = 1 << 30;
+
+// Hashes come from CSV files, so they are checked before becoming a path.
+pub(crate) fn object_path(store: &Path, hash: &str) -> Result<PathBuf> {
+    if HashAlgorithm::of_hash(hash).is_none()
+        || !hash
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    {
+        return Err(anyhow!("Wrong hash for the store: {}", hash));
+    }
+    Ok(store.join(STORE_OBJECTS).join(&hash[..2]).join(&hash[2..]))
 }
 
 pub fn copy_files(config: CopyFilesConfig) -> Result<()> {
     println!("COPY FILES | config: {:?}", config);
     let now = Instant::now();
     let mut ctx = Context::new(config)?;
+    if ctx.config.undo {
+        ctx.undo_moves()?;
+        println!("Duration: {:#?}", (Instant::now() - now));
+        println!(
+            "Moved back {} files",
+            ctx.lines_written.to_formatted_string(&Locale::en)
+        );
+        println!(
+            "Errors: {} ({:?})",
+            ctx.reporter.error_count().to_formatted_string(&Locale::en),
+            ctx.config.error_log
+        );
+        return Ok(());
+    }
     ctx.process()?;
     println!("Duration: {:#?}", (Instant::now() - now));
     println!(
-        "Copied {} files {:?}",
+        "{} {} files {:?}",
+        if ctx.config.move_files {
+            "Moved"
+        } else {
+            "Copied"
+        },
         ctx.lines_written.to_formatted_string(&Locale::en),
         ctx.config.target_folder
     );
+    if ctx.config.store {
+        println!(
+            "Deduplicated {} files already in the store",
+            ctx.deduplicated.to_formatted_string(&Locale::en)
+        );
+    }
+    if ctx.config.resume || ctx.skipped > 0 {
+        println!(
+            "Skipped {} files already in the output folder",
+            ctx.skipped.to_formatted_string(&Locale::en)
+        );
+    }
     println!(
-        "Disk space taken: {}B",
-        SizeFormatterSI::new(ctx.copied_size)
+        "Disk space taken: {}",
+        ctx.config.size_format.format(ctx.copied_size)
     );
+    if ctx.uses_exif {
+        println!(
+            "Without EXIF date: {} ({:?})",
+            ctx.no_exif.error_count().to_formatted_string(&Locale::en),
+            ctx.config.no_exif_log
+        );
+    }
     println!(
         "Errors: {} ({:?})",
         ctx.reporter.error_count().to_formatted_string(&Locale::en),
@@ -46,16 +127,51 @@ pub fn copy_files(config: CopyFilesConfig) -> Result<()> {
 struct Context {
     config: CopyFilesConfig,
     reporter: Reporter,
+    // Photos that fall back to their modification date.
+    no_exif: Reporter,
+    uses_exif: bool,
+    journal: Option<csv::Writer<File>>,
+    index: Option<csv::Writer<File>>,
     lines_written: u64,
+    deduplicated: u64,
+    skipped: u64,
     copied_size: u64,
 }
 
 impl Context {
     pub fn new(config: CopyFilesConfig) -> Result<Self> {
+        // The store decides where files go and never removes the sources.
+        if config.store
+            && (config.move_files
+                || config.undo
+                || config.resume
+                || config.flatten_output
+                || config.template.is_some()
+                || config.strip_prefix.is_some())
+        {
+            return Err(anyhow!(
+                "--store can't be used with --move, --undo, --resume, --flatten-output, --template or --strip-prefix"
+            ));
+        }
+        let journal = match (&config.journal, config.move_files && !config.undo) {
+            (Some(path), true) => Some(csv::Writer::from_path(path)?),
+            (None, true) => return Err(anyhow!("Moving files needs a journal to undo them")),
+            (_, false) => None,
+        };
         Ok(Context {
+            journal,
+            index: None,
+            deduplicated: 0,
             reporter: Reporter::new(config.error_log.clone(), config.debug),
+            no_exif: Reporter::new(config.no_exif_log.clone(), config.debug),
+            uses_exif: config.date_source == DateSource::Exif
+                && config
+                    .template
+                    .as_ref()
+                    .is_some_and(|template| template.uses_date()),
             config,
             lines_written: 0,
+            skipped: 0,
             copied_size: 0,
         })
     }
@@ -67,11 +183,30 @@ impl Context {
             let record: Record = record?;
             total_size += record.size;
         }
-        std::fs::create_dir(&Path::new(&self.config.target_folder))?;
-        let mut target_path_generator =
-            TargetPathGenerator::new(self.config.flatten_output, &self.config.target_folder);
+        if self.config.store {
+            // Every backup appends to the same store.
+            std::fs::create_dir_all(&self.config.target_folder)?;
+            let index_path = self.config.target_folder.join(STORE_INDEX);
+            let new_index = !index_path.exists();
+            let index = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(index_path)?;
+            self.index = Some(
+                csv::WriterBuilder::new()
+                    .has_headers(new_index)
+                    .from_writer(index),
+            );
+        } else if self.config.resume {
+            std::fs::create_dir_all(&self.config.target_folder)?;
+        } else {
+            std::fs::create_dir(&self.config.target_folder)?;
+        }
+        let mut target_path_generator = TargetPathGenerator::new(&self.config);
         let mut reader = csv::Reader::from_reader(File::open(&self.config.source_file)?);
         let mut current_size: u64 = 0;
+        let mut members = MemberBatch::default();
+        let mut members_size = 0;
         for record in reader.deserialize() {
             let record: Record = record?;
 
@@ -83,48 +218,454 @@ impl Context {
                 );
             }
 
-            let source_path = Path::new(&record.path);
-            let target_path = target_path_generator.get_target_path(source_path)?;
-            if !self.config.flatten_output {
-                std::fs::create_dir_all(&target_path.parent().ok_or(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Parent should be a dir",
-                ))?)?;
+            let source = match record.source() {
+                Ok(source) => source,
+                Err(e) => {
+                    self.reporter.report_error(&record.path, e)?;
+                    continue;
+                }
+            };
+            if let Some(member) = source.member.clone() {
+                // Members of one archive are extracted together, reading it
+                // only once, in batches that bound the room they take.
+                if !members.accepts(&member)
+                    || (!members.is_empty() && members_size + record.size > MEMBER_BATCH_SIZE)
+                {
+                    self.copy_members(&mut members, &mut target_path_generator)?;
+                    members_size = 0;
+                }
+                members_size += record.size;
+                members.push(member, (record, source));
+                continue;
+            }
+            self.copy_members(&mut members, &mut target_path_generator)?;
+            self.copy_record(&mut target_path_generator, record, source)?;
+        }
+        self.copy_members(&mut members, &mut target_path_generator)?;
+        if let Some(index) = &mut self.index {
+            index.flush()?;
+        }
+        println!();
+        Ok(())
+    }
+
+    fn copy_record(
+        &mut self,
+        target_path_generator: &mut TargetPathGenerator,
+        mut record: Record,
+        source: Source,
+    ) -> Result<()> {
+        let source_path = &source.path;
+        if self.config.store {
+            match self.store(&source, &mut record) {
+                Ok(()) => self.lines_written += 1,
+                Err(e) => self.reporter.report_error(&source_path, e)?,
+            }
+            return Ok(());
+        }
+        // The capture date takes the place of the modification date in
+        // the templates.
+        if self.uses_exif {
+            match exif_date(&source) {
+                Ok(Some(date)) => record.modified = Some(date),
+                Ok(None) => self
+                    .no_exif
+                    .report_error(&source_path, "No EXIF date, using the modification date")?,
+                Err(e) => self.reporter.report_error(&source_path, e)?,
+            }
+        }
+        let target_path = match target_path_generator.get_target_path(&source, &record) {
+            Ok(Some(target_path)) => target_path,
+            Ok(None) => {
+                self.skipped += 1;
+                return Ok(());
             }
-            if let Debug::On = self.config.debug {
-                print!("Copying {:?} to {:?}", source_path, target_path);
+            Err(e) => {
+                self.reporter.report_error(&source_path, e)?;
+                return Ok(());
             }
-            match std::fs::copy(&source_path, &target_path) {
-                Ok(size) => self.copied_size += size,
+        };
+        if self.config.template.is_some() || !self.config.flatten_output {
+            std::fs::create_dir_all(
+                target_path
+                    .parent()
+                    .ok_or_else(|| anyhow!("Parent should be a dir"))?,
+            )?;
+        }
+        if self.config.resume {
+            match self.already_copied(&source, &target_path, &record) {
+                Ok(true) => {
+                    self.skipped += 1;
+                    return Ok(());
+                }
+                Ok(false) => {}
                 Err(e) => {
                     self.reporter.report_error(&source_path, e)?;
-                    continue;
+                    return Ok(());
                 }
             }
-            debug_assert_eq!(
-                std::fs::metadata(source_path)?.modified()?,
-                std::fs::metadata(target_path)?.modified()?
-            );
+        }
+        if let Debug::On = self.config.debug {
+            print!("Copying {:?} to {:?}", source_path, target_path);
+        }
+        // Taken before copying, as reading the source may change its atime.
+        let metadata = if source.member.is_some() {
+            None
+        } else {
+            match std::fs::metadata(source_path) {
+                Ok(metadata) => Some(metadata),
+                Err(e) => {
+                    self.reporter.report_error(&source_path, e)?;
+                    return Ok(());
+                }
+            }
+        };
+        let result = if self.config.move_files {
+            self.move_file(&source, &target_path, &record, metadata.as_ref())
+        } else {
+            self.copy(&source, &target_path, &record)
+        };
+        match result {
+            Ok(size) => self.copied_size += size,
+            Err(e) => {
+                self.reporter.report_error(&source_path, e)?;
+                return Ok(());
+            }
+        }
+        // Moves take care of it, as the source is gone by now.
+        if let Some(metadata) = metadata.filter(|_| !self.config.move_files) {
+            if let Err(e) =
+                preserve_metadata(source_path, &metadata, &target_path, &self.config.preserve)
+            {
+                self.reporter.report_error(&source_path, e)?;
+            }
+        }
+
+        self.lines_written += 1;
+        Ok(())
+    }
+
+    // Members are extracted inside the output folder, and removed once the
+    // batch is copied.
+    fn copy_members(
+        &mut self,
+        members: &mut MemberBatch<(Record, Source)>,
+        target_path_generator: &mut TargetPathGenerator,
+    ) -> Result<()> {
+        if members.is_empty() {
+            return Ok(());
+        }
+        let dir = tempfile::Builder::new()
+            .prefix(".members")
+            .tempdir_in(&self.config.target_folder)?;
+        let mut extracted_files = 0;
+        let extracted = members.read(|(_, source), reader| {
+            let path = dir.path().join(extracted_files.to_string());
+            extracted_files += 1;
+            std::io::copy(reader, &mut File::create(&path)?)?;
+            source.extracted = Some(path);
+            Ok(())
+        });
+        for ((record, source), result) in extracted {
+            match result {
+                Ok(()) => self.copy_record(target_path_generator, record, source)?,
+                Err(e) => self.reporter.report_error(&source.path, e)?,
+            }
+        }
+        Ok(())
+    }
 
+    // Objects are named by a hash of the whole content, computed here since
+    // the input hashes may only cover the first bytes of the files. Files
+    // already in the store aren't copied again, only added to the index along
+    // with their modification date.
+    fn store(&mut self, source: &Source, record: &mut Record) -> Result<()> {
+        if source.member.is_none() {
+            let metadata = std::fs::metadata(&source.path)?;
+            record.size = metadata.len();
+            if record.modified.is_none() {
+                record.modified = modified_time(&metadata);
+            }
+        }
+        let algorithm = HashAlgorithm::of_hash(&record.hash).unwrap_or(HashAlgorithm::Md5);
+        record.hash = compute_hash(source, record.size, 0, algorithm)?;
+        record.whole_hash = true;
+        let object_path = object_path(&self.config.target_folder, &record.hash)?;
+        if self.already_copied(source, &object_path, record)? {
+            self.deduplicated += 1;
+        } else {
+            std::fs::create_dir_all(object_path.parent().unwrap())?;
+            self.copied_size += self.copy(source, &object_path, record)?;
+        }
+        if let Some(index) = &mut self.index {
+            index.serialize(&record)?;
+        }
+        Ok(())
+    }
+
+    // An existing copy is kept when it has the size of the source, and in
+    // verify mode its hash as well.
+    fn already_copied(&self, source: &Source, target_path: &Path, record: &Record) -> Result<bool> {
+        let metadata = match std::fs::metadata(target_path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        if metadata.len() != record.size {
+            return Ok(false);
+        }
+        match self.config.verify {
+            Some(algorithm) => {
+                let (source_hash, algorithm) = source_hash(source, record, algorithm)?;
+                Ok(compute_hash(target_path, record.size, 0, algorithm)? == source_hash)
+            }
+            None => Ok(true),
+        }
+    }
+
+    // Files are copied under a temporary name and renamed when complete, so
+    // an interrupted copy never looks like a finished one. In verify mode the
+    // copy is hashed and compared with the source, and copied again while
+    // they differ.
+    fn copy(&mut self, source: &Source, target_path: &Path, record: &Record) -> Result<u64> {
+        match self.config.verify {
+            Some(algorithm) => {
+                let source_hash = source_hash(source, record, algorithm)?;
+                self.verified_copy(source, target_path, source_hash, None)
+            }
+            None => {
+                let partial_path = partial_path(target_path)?;
+                let size = copy_source(source, &partial_path)?;
+                std::fs::rename(&partial_path, target_path)?;
+                Ok(size)
+            }
+        }
+    }
+
+    fn verified_copy(
+        &mut self,
+        source: &Source,
+        target_path: &Path,
+        (source_hash, algorithm): (String, HashAlgorithm),
+        metadata: Option<&Metadata>,
+    ) -> Result<u64> {
+        let partial_path = partial_path(target_path)?;
+        let source_path = &source.path;
+        for _ in 0..=self.config.verify_retries {
+            let size = copy_source(source, &partial_path)?;
+            let target_hash = compute_hash(&partial_path, size, 0, algorithm)?;
+            if target_hash == source_hash {
+                if let Some(metadata) = metadata {
+                    let preserve = &self.config.preserve;
+                    if let Err(e) =
+                        preserve_metadata(source_path, metadata, &partial_path, preserve)
+                    {
+                        self.reporter.report_error(&source_path, e)?;
+                    }
+                }
+                std::fs::rename(&partial_path, target_path)?;
+                return Ok(size);
+            }
+            self.reporter.report_error(
+                &source_path,
+                anyhow!(
+                    "Copy {:?} has hash {} instead of {}",
+                    target_path,
+                    target_hash,
+                    source_hash
+                ),
+            )?;
+            std::fs::remove_file(&partial_path)?;
+        }
+        Err(anyhow!(
+            "Couldn't make a verified copy after {} attempts",
+            self.config.verify_retries + 1
+        ))
+    }
+
+    // Renames the file when possible, otherwise it's copied, verified, given
+    // the metadata of the source and then deleted. Every move is written to
+    // the journal right away, so it can be undone even if the process is
+    // interrupted.
+    fn move_file(
+        &mut self,
+        source: &Source,
+        target_path: &Path,
+        record: &Record,
+        metadata: Option<&Metadata>,
+    ) -> Result<u64> {
+        if source.member.is_some() {
+            return Err(anyhow!("Files inside archives can't be moved"));
+        }
+        let source_path = &source.path;
+        let size = match std::fs::rename(source_path, target_path) {
+            Ok(()) => std::fs::metadata(target_path)?.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+                // The source is removed afterwards, so the copy is checked
+                // against the file itself rather than the hash in the list.
+                let algorithm = self.config.verify.unwrap_or(HashAlgorithm::Md5);
+                let source_hash = compute_hash(source, record.size, 0, algorithm)?;
+                let size =
+                    self.verified_copy(source, target_path, (source_hash, algorithm), metadata)?;
+                std::fs::remove_file(source_path)?;
+                size
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(journal) = &mut self.journal {
+            journal.serialize(Move {
+                source: encode_path(source_path)?,
+                target: encode_path(target_path)?,
+            })?;
+            journal.flush()?;
+        }
+        Ok(size)
+    }
+
+    // Files are moved back in reverse order, and never over existing files.
+    pub fn undo_moves(&mut self) -> Result<()> {
+        let mut reader = csv::Reader::from_reader(File::open(&self.config.source_file)?);
+        let moves = reader.deserialize().collect::<Result<Vec<Move>, _>>()?;
+        for entry in moves.iter().rev() {
+            if let Err(e) = undo_move(entry) {
+                self.reporter.report_error(&entry.target, e)?;
+                continue;
+            }
             self.lines_written += 1;
         }
-        println!();
         Ok(())
     }
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+struct Move {
+    source: String,
+    target: String,
+}
+
+fn undo_move(entry: &Move) -> Result<()> {
+    let source_path = decode_path(&entry.source)?;
+    let target_path = decode_path(&entry.target)?;
+    if source_path.exists() {
+        return Err(anyhow!("There is already a file at {:?}", source_path));
+    }
+    if let Some(parent) = source_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match std::fs::rename(&target_path, &source_path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            let metadata = std::fs::metadata(&target_path)?;
+            let partial_path = partial_path(&source_path)?;
+            copy_source(&target_path, &partial_path)?;
+            let preserve = [Preserve::Timestamps, Preserve::Mode];
+            preserve_metadata(&target_path, &metadata, &partial_path, &preserve)?;
+            std::fs::rename(&partial_path, &source_path)?;
+            Ok(std::fs::remove_file(&target_path)?)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// The hash in the record is only trusted when it covers the whole file.
+fn source_hash(
+    source: &Source,
+    record: &Record,
+    algorithm: HashAlgorithm,
+) -> Result<(String, HashAlgorithm)> {
+    match HashAlgorithm::of_hash(&record.hash) {
+        Some(record_algorithm) if record.whole_hash => Ok((record.hash.clone(), record_algorithm)),
+        _ => Ok((compute_hash(source, record.size, 0, algorithm)?, algorithm)),
+    }
+}
+
+pub(crate) fn partial_path(target_path: &Path) -> Result<PathBuf> {
+    let file_name = target_path
+        .file_name()
+        .ok_or_else(|| anyhow!("Can't get filename from target path"))?;
+    let mut partial_name = OsString::from(".");
+    partial_name.push(file_name);
+    partial_name.push(".partial");
+    Ok(target_path.with_file_name(partial_name))
+}
+
+// The mode goes last, as changing the owner clears the setuid bits and a
+// read-only mode would get in the way of the rest.
+fn preserve_metadata(
+    source: &Path,
+    metadata: &Metadata,
+    target: &Path,
+    preserve: &[Preserve],
+) -> Result<()> {
+    #[cfg(unix)]
+    {
+        if preserve.contains(&Preserve::Xattrs) {
+            copy_xattrs(source, target)?;
+        }
+        if preserve.contains(&Preserve::Ownership) {
+            copy_ownership(metadata, target)?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = source;
+    if preserve.contains(&Preserve::Timestamps) {
+        let times = FileTimes::new()
+            .set_accessed(metadata.accessed()?)
+            .set_modified(metadata.modified()?);
+        OpenOptions::new()
+            .write(true)
+            .open(target)?
+            .set_times(times)?;
+    }
+    if preserve.contains(&Preserve::Mode) {
+        std::fs::set_permissions(target, metadata.permissions())?;
+    }
+    Ok(())
+}
+
+// ACLs are stored as extended attributes as well.
+#[cfg(unix)]
+fn copy_xattrs(source: &Path, target: &Path) -> Result<()> {
+    for name in xattr::list(source)? {
+        if let Some(value) = xattr::get(source, &name)? {
+            xattr::set(target, &name, &value)?;
+        }
+    }
+    Ok(())
+}
+
+// Only root can give files away, other users just keep owning the copies.
+#[cfg(unix)]
+fn copy_ownership(metadata: &Metadata, target: &Path) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    match std::os::unix::fs::chown(target, Some(metadata.uid()), Some(metadata.gid())) {
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Ok(()),
+        result => Ok(result?),
+    }
+}
+
 struct TargetPathGenerator {
     flatten: bool,
+    template: Option<PathTemplate>,
+    strip_prefix: Option<PathBuf>,
+    collisions: CollisionStrategy,
+    // Files already in the target folder are collisions too, except when
+    // resuming, as they are likely the copies of the interrupted run.
+    check_disk: bool,
     target_folder: PathBuf,
     paths: HashSet<OsString>,
     regex: Regex,
 }
 
 impl TargetPathGenerator {
-    pub fn new(flatten: bool, target_folder: &PathBuf) -> Self {
+    pub fn new(config: &CopyFilesConfig) -> Self {
         TargetPathGenerator {
-            flatten,
-            target_folder: target_folder.clone(),
+            flatten: config.flatten_output,
+            template: config.template.clone(),
+            strip_prefix: config.strip_prefix.clone(),
+            collisions: config.collisions,
+            check_disk: !config.resume,
+            target_folder: config.target_folder.clone(),
             paths: HashSet::new(),
             regex: Regex::new(r".* - Copy \((?P<times>\d+)\)$").unwrap(),
         }
@@ -132,64 +673,185 @@ impl TargetPathGenerator {
 }
 
 impl TargetPathGenerator {
-    fn get_target_path(&mut self, source_path: &Path) -> Result<PathBuf> {
-        if self.flatten {
-            let mut file_name =
-                OsString::from(source_path.file_name().ok_or(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Can't get filename from source path",
-                ))?);
-            while self.paths.contains(&file_name.to_os_string()) {
-                let mut file_stem = Path::new(&file_name).file_stem().unwrap().to_owned();
-                let mut changed = false;
-                if let Some(stem_str) = file_stem.to_str() {
-                    if let Some(caps) = self.regex.captures(&stem_str) {
-                        let times = &caps["times"];
-                        if let Ok(count) = times.parse::<u64>() {
-                            let limit_to_common_part =
-                                file_stem.len() - (times.len() + " - Copy ()".len());
-                            file_stem = match file_stem.into_string() {
-                                Ok(string) => OsString::from(&string[0..limit_to_common_part]),
-                                Err(string) => {
-                                    return Err(anyhow!(
-                                        "Wrong unicode for this one: {:?}",
-                                        string
-                                    ));
-                                }
-                            };
-                            file_stem.push(&format!(" - Copy ({})", count + 1));
-                            changed = true;
-                        }
+    // Returns None when the file doesn't need to be copied, because a file
+    // with the same name and content is already there.
+    fn get_target_path(&mut self, source: &Source, record: &Record) -> Result<Option<PathBuf>> {
+        let source_path = &source.path;
+        let relative_path = self.relative_path(source_path)?;
+        // Names relative to the target folder, tracked to avoid collisions.
+        let mut file_name = if let Some(template) = &self.template {
+            let template_source = TemplateSource {
+                source,
+                relative_path: &relative_path,
+                record,
+            };
+            template
+                .render(&template_source, || file_date(source, record))?
+                .into_os_string()
+        } else if self.flatten {
+            OsString::from(
+                source_path
+                    .file_name()
+                    .ok_or_else(|| anyhow!("Can't get filename from source path"))?,
+            )
+        } else {
+            return Ok(Some(self.target_folder.join(relative_path)));
+        };
+        if self.is_taken(&file_name) {
+            match self.collisions {
+                CollisionStrategy::HashSuffix => {
+                    let hash = record_hash(source, record)?;
+                    let suffix = format!("-{}", &hash[..SHORT_HASH_LEN.min(hash.len())]);
+                    file_name = with_suffix(&file_name, &suffix);
+                }
+                CollisionStrategy::ParentFolder => {
+                    if let Some(parent) = source_path.parent().and_then(|parent| parent.file_name())
+                    {
+                        let suffix = format!(" ({})", parent.to_string_lossy());
+                        file_name = with_suffix(&file_name, &suffix);
                     }
                 }
-                if !changed {
-                    file_stem.push(" - Copy (1)");
+                CollisionStrategy::CopySuffix | CollisionStrategy::SkipIdentical => {}
+            }
+        }
+        while self.is_taken(&file_name) {
+            if let CollisionStrategy::SkipIdentical = self.collisions {
+                if same_content(source, &self.target_folder.join(&file_name))? {
+                    return Ok(None);
                 }
-                if let Some(extension) = source_path.extension() {
-                    file_stem.push(".");
-                    file_stem.push(extension);
+            }
+            file_name = self.next_copy_name(&file_name)?;
+        }
+        self.paths.insert(file_name.clone());
+        Ok(Some(self.target_folder.join(file_name)))
+    }
+
+    // The source path without its root, or without the stripped prefix.
+    fn relative_path(&self, source_path: &Path) -> Result<PathBuf> {
+        match &self.strip_prefix {
+            Some(prefix) => Ok(source_path
+                .strip_prefix(prefix)
+                .map_err(|_| anyhow!("Path isn't inside the stripped prefix {:?}", prefix))?
+                .to_owned()),
+            None if source_path.has_root() => {
+                Ok(source_path.components().skip(1).collect::<PathBuf>())
+            }
+            None => Ok(source_path.to_owned()),
+        }
+    }
+
+    fn is_taken(&self, file_name: &OsString) -> bool {
+        self.paths.contains(file_name)
+            || (self.check_disk && self.target_folder.join(file_name).exists())
+    }
+
+    fn next_copy_name(&self, file_name: &OsString) -> Result<OsString> {
+        let mut file_stem = Path::new(&file_name).file_stem().unwrap().to_owned();
+        if let Some(stem_str) = file_stem.to_str() {
+            if let Some(caps) = self.regex.captures(stem_str) {
+                let times = &caps["times"];
+                if let Ok(count) = times.parse::<u64>() {
+                    let limit_to_common_part = file_stem.len() - (times.len() + " - Copy ()".len());
+                    file_stem = match file_stem.into_string() {
+                        Ok(string) => OsString::from(&string[0..limit_to_common_part]),
+                        Err(string) => {
+                            return Err(anyhow!("Wrong unicode for this one: {:?}", string));
+                        }
+                    };
+                    file_stem.push(format!(" - Copy ({})", count + 1));
+                    return Ok(with_stem(file_name, file_stem));
                 }
-                file_name = file_stem;
             }
-            let target_path = self.target_folder.join(file_name);
-            Ok(target_path)
-        } else {
-            let target_path = self.target_folder.join(if source_path.has_root() {
-                source_path.components().skip(1).collect::<PathBuf>()
-            } else {
-                source_path.to_owned()
-            });
-            Ok(target_path)
         }
+        Ok(with_suffix(file_name, " - Copy (1)"))
     }
 }
 
+const SHORT_HASH_LEN: usize = 8;
+
+// Puts the suffix between the stem and the extension of the file name.
+fn with_suffix(file_name: &OsString, suffix: &str) -> OsString {
+    let mut file_stem = Path::new(file_name).file_stem().unwrap().to_owned();
+    file_stem.push(suffix);
+    with_stem(file_name, file_stem)
+}
+
+// Replaces the last component of the path, keeping its folders.
+fn with_stem(file_name: &OsString, mut file_stem: OsString) -> OsString {
+    if let Some(extension) = Path::new(file_name).extension() {
+        file_stem.push(".");
+        file_stem.push(extension);
+    }
+    Path::new(file_name)
+        .with_file_name(file_stem)
+        .into_os_string()
+}
+
+// Templates use the modification date in the record, or the one of the file
+// for older lists.
+fn file_date(source: &Source, record: &Record) -> Result<i64> {
+    if let Some(modified) = record.modified {
+        return Ok(modified);
+    }
+    if source.member.is_some() {
+        return Err(anyhow!("No modification date for a file inside an archive"));
+    }
+    modified_time(&std::fs::metadata(&source.path)?)
+        .ok_or_else(|| anyhow!("No modification date for this file"))
+}
+
+fn same_content(source: &Source, target_path: &Path) -> Result<bool> {
+    let mut target = match File::open(target_path) {
+        Ok(file) => std::io::BufReader::new(file),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    with_source(source, |source| {
+        let mut source = std::io::BufReader::new(source);
+        loop {
+            let source_buffer = source.fill_buf()?;
+            let target_buffer = target.fill_buf()?;
+            let len = source_buffer.len().min(target_buffer.len());
+            if source_buffer[..len] != target_buffer[..len] {
+                return Ok(false);
+            }
+            if len == 0 {
+                return Ok(source_buffer.is_empty() && target_buffer.is_empty());
+            }
+            source.consume(len);
+            target.consume(len);
+        }
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    fn file(path: impl Into<PathBuf>) -> Source {
+        Source {
+            path: path.into(),
+            member: None,
+            extracted: None,
+        }
+    }
+
+    fn generator(
+        flatten: bool,
+        collisions: CollisionStrategy,
+        target: &Path,
+    ) -> TargetPathGenerator {
+        TargetPathGenerator::new(&CopyFilesConfig {
+            flatten_output: flatten,
+            collisions,
+            target_folder: target.to_owned(),
+            ..CopyFilesConfig::default()
+        })
+    }
+
     fn get_target_path(flatten: bool, source: &str, target: &str) -> String {
-        let mut gen = TargetPathGenerator::new(flatten, &PathBuf::from(target));
-        format!("{:?}", gen.get_target_path(Path::new(source)).unwrap())
+        let mut gen = generator(flatten, Default::default(), Path::new(target));
+        let target_path = gen.get_target_path(&file(source), &Record::for_test("", 0, "NULL"));
+        format!("{:?}", target_path.unwrap().unwrap())
     }
 
     fn get_target_path_substitutes(
@@ -198,11 +860,518 @@ mod test {
         target: &str,
         already: &[&str],
     ) -> String {
-        let mut gen = TargetPathGenerator::new(flatten, &PathBuf::from(target));
+        get_target_path_with(
+            flatten,
+            CollisionStrategy::CopySuffix,
+            source,
+            target,
+            already,
+        )
+    }
+
+    fn get_target_path_with(
+        flatten: bool,
+        collisions: CollisionStrategy,
+        source: &str,
+        target: &str,
+        already: &[&str],
+    ) -> String {
+        let mut gen = generator(flatten, collisions, Path::new(target));
         already
             .iter()
             .for_each(|path| assert_eq!(true, gen.paths.insert(std::ffi::OsString::from(path))));
-        format!("{:?}", gen.get_target_path(Path::new(source)).unwrap())
+        let target_path = gen.get_target_path(
+            &file(source),
+            &Record::for_test("", 0, "0123456789abcdef0123456789abcdef"),
+        );
+        format!("{:?}", target_path.unwrap().unwrap())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_preserve_timestamps_and_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, SystemTime};
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("a.txt");
+        let target = dir.path().join("b.txt");
+        std::fs::write(&source, "a").unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        OpenOptions::new()
+            .write(true)
+            .open(&source)
+            .unwrap()
+            .set_times(FileTimes::new().set_modified(modified))
+            .unwrap();
+        std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o640)).unwrap();
+        std::fs::write(&target, "a").unwrap();
+        let metadata = std::fs::metadata(&source).unwrap();
+        let preserve = [Preserve::Timestamps, Preserve::Mode, Preserve::Ownership];
+        preserve_metadata(&source, &metadata, &target, &preserve).unwrap();
+        let actual = std::fs::metadata(&target).unwrap();
+        assert_eq!(actual.modified().unwrap(), modified);
+        assert_eq!(actual.permissions().mode() & 0o777, 0o640);
+    }
+
+    #[test]
+    fn test_verify_removes_copies_not_matching_the_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let good = dir.path().join("good.txt");
+        let bad = dir.path().join("bad.txt");
+        std::fs::write(&good, "good").unwrap();
+        std::fs::write(&bad, "bad").unwrap();
+        let source_file = dir.path().join("in.csv");
+        std::fs::write(
+            &source_file,
+            format!(
+                "path,size,hash,whole_hash\n{},4,NULL,false\n{},3,{},true\n",
+                good.display(),
+                bad.display(),
+                "0".repeat(32)
+            ),
+        )
+        .unwrap();
+        let config = CopyFilesConfig {
+            source_file,
+            target_folder: dir.path().join("out"),
+            flatten_output: true,
+            verify: Some(HashAlgorithm::Md5),
+            verify_retries: 1,
+            ..CopyFilesConfig::default()
+        };
+        let mut ctx = Context::new(config).unwrap();
+        ctx.process().unwrap();
+        assert_eq!(ctx.lines_written, 1);
+        assert_eq!(ctx.reporter.error_count(), 3);
+        assert!(dir.path().join("out/good.txt").exists());
+        assert!(!dir.path().join("out/bad.txt").exists());
+    }
+
+    #[test]
+    fn test_verify_recomputes_partial_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("a.txt");
+        std::fs::write(&source, "abcdef").unwrap();
+        let source_file = dir.path().join("in.csv");
+        // The hash of the first 3 bytes, as written by `hash-paths -b 3`
+        std::fs::write(
+            &source_file,
+            format!(
+                "path,size,hash,whole_hash\n{},6,{},false\n",
+                source.display(),
+                compute_hash(&source, 6, 3, HashAlgorithm::Md5).unwrap()
+            ),
+        )
+        .unwrap();
+        let config = CopyFilesConfig {
+            source_file,
+            target_folder: dir.path().join("out"),
+            flatten_output: true,
+            verify: Some(HashAlgorithm::Md5),
+            ..CopyFilesConfig::default()
+        };
+        let mut ctx = Context::new(config).unwrap();
+        ctx.process().unwrap();
+        assert_eq!(ctx.lines_written, 1);
+        assert_eq!(ctx.reporter.error_count(), 0);
+        assert!(dir.path().join("out/a.txt").exists());
+    }
+
+    #[test]
+    fn test_resume_skips_finished_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        std::fs::create_dir(&out).unwrap();
+        std::fs::write(dir.path().join("a.txt"), "aaa").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "bbb").unwrap();
+        std::fs::write(out.join("a.txt"), "AAA").unwrap();
+        std::fs::write(out.join("b.txt"), "b").unwrap();
+        std::fs::write(out.join(".b.txt.partial"), "bb").unwrap();
+        let source_file = dir.path().join("in.csv");
+        std::fs::write(
+            &source_file,
+            format!(
+                "path,size,hash\n{},3,NULL\n{},3,NULL\n",
+                dir.path().join("a.txt").display(),
+                dir.path().join("b.txt").display()
+            ),
+        )
+        .unwrap();
+        let config = CopyFilesConfig {
+            source_file,
+            target_folder: out.clone(),
+            flatten_output: true,
+            resume: true,
+            ..CopyFilesConfig::default()
+        };
+        let mut ctx = Context::new(config).unwrap();
+        ctx.process().unwrap();
+        assert_eq!((ctx.skipped, ctx.lines_written), (1, 1));
+        assert_eq!(std::fs::read_to_string(out.join("a.txt")).unwrap(), "AAA");
+        assert_eq!(std::fs::read_to_string(out.join("b.txt")).unwrap(), "bbb");
+        assert!(!out.join(".b.txt.partial").exists());
+    }
+
+    #[test]
+    fn test_flatten_collisions_are_tracked() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("c.jpg"), "c").unwrap();
+        let mut gen = generator(true, CollisionStrategy::CopySuffix, dir.path());
+        let mut target_name = |source: &str| {
+            let target_path = gen.get_target_path(&file(source), &Record::for_test("", 0, "NULL"));
+            target_path
+                .unwrap()
+                .unwrap()
+                .file_name()
+                .unwrap()
+                .to_owned()
+        };
+        assert_eq!(target_name("/a/b.jpg"), "b.jpg");
+        assert_eq!(target_name("/b/b.jpg"), "b - Copy (1).jpg");
+        assert_eq!(target_name("/a/c.jpg"), "c - Copy (1).jpg");
+    }
+
+    #[test]
+    fn test_flatten_skips_identical_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        std::fs::create_dir(&out).unwrap();
+        std::fs::write(out.join("a.txt"), "same").unwrap();
+        std::fs::write(dir.path().join("a.txt"), "same").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "other").unwrap();
+        let mut gen = generator(true, CollisionStrategy::SkipIdentical, &out);
+        let same = gen.get_target_path(
+            &file(dir.path().join("a.txt")),
+            &Record::for_test("", 0, "NULL"),
+        );
+        assert_eq!(same.unwrap(), None);
+        std::fs::write(out.join("b.txt"), "b").unwrap();
+        let other = gen.get_target_path(
+            &file(dir.path().join("b.txt")),
+            &Record::for_test("", 0, "NULL"),
+        );
+        assert_eq!(other.unwrap(), Some(out.join("b - Copy (1).txt")));
+    }
+
+    #[test]
+    fn test_template_collisions_keep_folders() {
+        let mut gen = TargetPathGenerator::new(&CopyFilesConfig {
+            template: Some("{extension}/{name}".parse().unwrap()),
+            strip_prefix: Some(PathBuf::from("/la")),
+            target_folder: PathBuf::from("/out"),
+            resume: true,
+            ..CopyFilesConfig::default()
+        });
+        let mut target_path = |source: &str| {
+            let target_path = gen.get_target_path(&file(source), &Record::for_test("", 0, "NULL"));
+            target_path.unwrap().unwrap()
+        };
+        assert_eq!(
+            target_path("/la/le/li.v1.mp3"),
+            Path::new("/out/mp3/li.v1.mp3")
+        );
+        assert_eq!(
+            target_path("/la/lo/li.v1.mp3"),
+            Path::new("/out/mp3/li.v1 - Copy (1).mp3")
+        );
+        assert!(gen
+            .get_target_path(&file("/lu/li.mp3"), &Record::for_test("", 0, "NULL"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_template_collisions_keep_rendered_extension() {
+        let mut gen = TargetPathGenerator::new(&CopyFilesConfig {
+            template: Some("{name}.bak".parse().unwrap()),
+            target_folder: PathBuf::from("/out"),
+            resume: true,
+            collisions: CollisionStrategy::ParentFolder,
+            ..CopyFilesConfig::default()
+        });
+        let mut target_path = |source: &str| {
+            let target_path = gen.get_target_path(&file(source), &Record::for_test("", 0, "NULL"));
+            target_path.unwrap().unwrap()
+        };
+        assert_eq!(target_path("/la/li.mp3"), Path::new("/out/li.mp3.bak"));
+        assert_eq!(target_path("/lo/li.mp3"), Path::new("/out/li.mp3 (lo).bak"));
+        assert_eq!(
+            target_path("/lo/li.mp3"),
+            Path::new("/out/li.mp3 (lo) - Copy (1).bak")
+        );
+    }
+
+    #[test]
+    fn test_strip_prefix_when_mirroring() {
+        let mut gen = TargetPathGenerator::new(&CopyFilesConfig {
+            strip_prefix: Some(PathBuf::from("/la/le")),
+            target_folder: PathBuf::from("/out"),
+            ..CopyFilesConfig::default()
+        });
+        let target_path =
+            gen.get_target_path(&file("/la/le/li/lo.mp3"), &Record::for_test("", 0, "NULL"));
+        assert_eq!(target_path.unwrap().unwrap(), Path::new("/out/li/lo.mp3"));
+    }
+
+    #[test]
+    fn test_move_and_undo() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("in/a.txt");
+        std::fs::create_dir(dir.path().join("in")).unwrap();
+        std::fs::write(&source, "a").unwrap();
+        let source_file = dir.path().join("in.csv");
+        std::fs::write(
+            &source_file,
+            format!("path,size,hash\n{},1,NULL\n", source.display()),
+        )
+        .unwrap();
+        let journal = dir.path().join("journal.csv");
+        let config = CopyFilesConfig {
+            source_file,
+            target_folder: dir.path().join("out"),
+            flatten_output: true,
+            move_files: true,
+            journal: Some(journal.clone()),
+            ..CopyFilesConfig::default()
+        };
+        let mut ctx = Context::new(config).unwrap();
+        ctx.process().unwrap();
+        drop(ctx);
+        assert!(!source.exists());
+        assert!(dir.path().join("out/a.txt").exists());
+        std::fs::remove_dir(dir.path().join("in")).unwrap();
+        let config = CopyFilesConfig {
+            source_file: journal,
+            undo: true,
+            ..CopyFilesConfig::default()
+        };
+        let mut ctx = Context::new(config).unwrap();
+        ctx.undo_moves().unwrap();
+        assert_eq!(ctx.lines_written, 1);
+        assert_eq!(std::fs::read_to_string(&source).unwrap(), "a");
+        assert!(!dir.path().join("out/a.txt").exists());
+    }
+
+    #[test]
+    fn test_move_with_preserve_reports_no_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("a.txt");
+        std::fs::write(&source, "a").unwrap();
+        let source_file = dir.path().join("in.csv");
+        std::fs::write(
+            &source_file,
+            format!("path,size,hash\n{},1,NULL\n", source.display()),
+        )
+        .unwrap();
+        let config = CopyFilesConfig {
+            source_file,
+            target_folder: dir.path().join("out"),
+            flatten_output: true,
+            move_files: true,
+            journal: Some(dir.path().join("journal.csv")),
+            preserve: vec![Preserve::Xattrs, Preserve::Mode],
+            ..CopyFilesConfig::default()
+        };
+        let mut ctx = Context::new(config).unwrap();
+        ctx.process().unwrap();
+        assert_eq!(ctx.reporter.error_count(), 0);
+        assert_eq!(ctx.lines_written, 1);
+        assert!(!source.exists());
+        assert!(dir.path().join("out/a.txt").exists());
+    }
+
+    #[test]
+    fn test_copy_archive_members() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("a.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+        for (name, content) in &[("a.txt", "first"), ("b.txt", "second")] {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            std::io::Write::write_all(&mut zip, content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        let source_file = dir.path().join("in.csv");
+        std::fs::write(
+            &source_file,
+            format!(
+                "path,size,hash,modified,dir_modified,in_archive\n\
+                 {0}!/a.txt,5,NULL,,,true\n{0}!/missing,1,NULL,,,true\n{0}!/b.txt,6,NULL,,,true\n",
+                archive.display()
+            ),
+        )
+        .unwrap();
+        let target_folder = dir.path().join("out");
+        let config = CopyFilesConfig {
+            source_file,
+            target_folder: target_folder.clone(),
+            flatten_output: true,
+            verify: Some(HashAlgorithm::Md5),
+            ..CopyFilesConfig::default()
+        };
+        let mut ctx = Context::new(config).unwrap();
+        ctx.process().unwrap();
+        assert_eq!(ctx.reporter.error_count(), 1);
+        assert_eq!(ctx.lines_written, 2);
+        let mut copied: Vec<(String, String)> = std::fs::read_dir(&target_folder)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let name = path.file_name().unwrap().to_str().unwrap().to_string();
+                (name, std::fs::read_to_string(&path).unwrap())
+            })
+            .collect();
+        copied.sort();
+        assert_eq!(
+            copied,
+            vec![
+                ("a.txt".to_string(), "first".to_string()),
+                ("b.txt".to_string(), "second".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_store_deduplicates_and_appends_to_the_index() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "same").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "same").unwrap();
+        let source_file = dir.path().join("in.csv");
+        std::fs::write(
+            &source_file,
+            format!(
+                "path,size,hash\n{},4,NULL\n{},4,NULL\n",
+                dir.path().join("a.txt").display(),
+                dir.path().join("b.txt").display()
+            ),
+        )
+        .unwrap();
+        let store = dir.path().join("store");
+        for _ in 0..2 {
+            let config = CopyFilesConfig {
+                source_file: source_file.clone(),
+                target_folder: store.clone(),
+                store: true,
+                ..CopyFilesConfig::default()
+            };
+            let mut ctx = Context::new(config).unwrap();
+            ctx.process().unwrap();
+            assert_eq!(ctx.lines_written, 2);
+        }
+        // md5 of "same"
+        let object = object_path(&store, "51037a4a37730f52c8732586d3aaa316").unwrap();
+        assert_eq!(std::fs::read_to_string(object).unwrap(), "same");
+        let mut index = csv::Reader::from_path(store.join(STORE_INDEX)).unwrap();
+        let records: Vec<Record> = index.deserialize().map(|record| record.unwrap()).collect();
+        assert_eq!(records.len(), 4);
+        assert!(records[3].path.ends_with("b.txt"));
+        assert!(records[3].modified.is_some());
+    }
+
+    #[test]
+    fn test_store_rejects_other_layouts_and_moves() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = |config: CopyFilesConfig| CopyFilesConfig {
+            target_folder: dir.path().join("store"),
+            journal: Some(dir.path().join("journal.csv")),
+            store: true,
+            ..config
+        };
+        for config in [
+            CopyFilesConfig {
+                move_files: true,
+                ..CopyFilesConfig::default()
+            },
+            CopyFilesConfig {
+                resume: true,
+                ..CopyFilesConfig::default()
+            },
+            CopyFilesConfig {
+                flatten_output: true,
+                ..CopyFilesConfig::default()
+            },
+            CopyFilesConfig {
+                template: Some("{name}".parse().unwrap()),
+                ..CopyFilesConfig::default()
+            },
+        ] {
+            assert!(Context::new(store(config)).is_err());
+        }
+        assert!(!dir.path().join("journal.csv").exists());
+    }
+
+    #[test]
+    fn test_store_hashes_whole_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "same start").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "same end").unwrap();
+        let source_file = dir.path().join("in.csv");
+        // Both files have the hash of their first 4 bytes.
+        std::fs::write(
+            &source_file,
+            format!(
+                "path,size,hash\n{},10,51037a4a37730f52c8732586d3aaa316\n{},8,51037a4a37730f52c8732586d3aaa316\n",
+                dir.path().join("a.txt").display(),
+                dir.path().join("b.txt").display()
+            ),
+        )
+        .unwrap();
+        let store = dir.path().join("store");
+        let config = CopyFilesConfig {
+            source_file,
+            target_folder: store.clone(),
+            store: true,
+            ..CopyFilesConfig::default()
+        };
+        let mut ctx = Context::new(config).unwrap();
+        ctx.process().unwrap();
+        assert_eq!(ctx.deduplicated, 0);
+        let mut index = csv::Reader::from_path(store.join(STORE_INDEX)).unwrap();
+        let records: Vec<Record> = index.deserialize().map(|record| record.unwrap()).collect();
+        for (record, content) in records.iter().zip(&["same start", "same end"]) {
+            let object = object_path(&store, &record.hash).unwrap();
+            assert_eq!(std::fs::read_to_string(object).unwrap(), *content);
+        }
+    }
+
+    #[test]
+    fn test_store_replaces_objects_of_the_wrong_size() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "same").unwrap();
+        let source_file = dir.path().join("in.csv");
+        std::fs::write(
+            &source_file,
+            format!(
+                "path,size,hash\n{},4,NULL\n",
+                dir.path().join("a.txt").display()
+            ),
+        )
+        .unwrap();
+        let store = dir.path().join("store");
+        let object = object_path(&store, "51037a4a37730f52c8732586d3aaa316").unwrap();
+        std::fs::create_dir_all(object.parent().unwrap()).unwrap();
+        std::fs::write(&object, "sa").unwrap();
+        let config = CopyFilesConfig {
+            source_file,
+            target_folder: store,
+            store: true,
+            ..CopyFilesConfig::default()
+        };
+        let mut ctx = Context::new(config).unwrap();
+        ctx.process().unwrap();
+        assert_eq!(ctx.deduplicated, 0);
+        assert_eq!(std::fs::read_to_string(object).unwrap(), "same");
+    }
+
+    #[test]
+    fn test_object_path_rejects_wrong_hashes() {
+        let store = Path::new("/store");
+        assert_eq!(
+            object_path(store, "51037a4a37730f52c8732586d3aaa316").unwrap(),
+            Path::new("/store/objects/51/037a4a37730f52c8732586d3aaa316")
+        );
+        assert!(object_path(store, "51037A4A37730F52C8732586D3AAA316").is_err());
+        assert!(object_path(store, "../../../../../../../../../../etc/passwd").is_err());
+        assert!(object_path(store, "NULL").is_err());
     }
 
     macro_rules! eq_tests {
@@ -246,6 +1415,20 @@ mod test {
                     "/out/",
                     &["li - Copy (10)"]
                 ) => "\"/out/li - Copy (11)\"";
+        get_flatten_target_path_with_hash_suffix: get_target_path_with(
+                    true,
+                    CollisionStrategy::HashSuffix,
+                    "/la/le/li.mp3",
+                    "/out/",
+                    &["li.mp3"]
+                ) => "\"/out/li-01234567.mp3\"";
+        get_flatten_target_path_with_parent_folder: get_target_path_with(
+                    true,
+                    CollisionStrategy::ParentFolder,
+                    "/la/le/li.mp3",
+                    "/out/",
+                    &["li.mp3", "li (le).mp3"]
+                ) => "\"/out/li (le) - Copy (1).mp3\"";
         get_flatten_target_misleading_path_uses_first_substitute: get_target_path_substitutes(true, "/la/le/li - Copy (x)", "/out/", &["li - Copy (x)"]) => "\"/out/li - Copy (x) - Copy (1)\"";
     }
 }
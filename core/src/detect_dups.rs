@@ -103,7 +103,7 @@ impl Context {
                 } else {
                     write!(output, ", ")?;
                 }
-                write!(output, "\"{}\"", p)?;
+                write!(output, "{}", serde_json::to_string(&p)?)?;
                 self.paths_included += 1;
             }
             write!(output, "]")?;
@@ -0,0 +1,100 @@
+use crate::internals::{with_source, AsSource};
+use anyhow::Result;
+use chrono::{Local, NaiveDate, TimeZone};
+use exif::{In, Reader, Tag, Value};
+use std::fs::File;
+use std::io::{BufReader, Cursor};
+
+// When a photo was taken, from the DateTimeOriginal tag of its EXIF data.
+// Cameras store it without time zone, so it's read as local time. Works with
+// JPEG, TIFF (and the raw formats built on it), HEIF, PNG and WebP files.
+pub fn exif_date(source: &(impl AsSource + ?Sized)) -> Result<Option<i64>> {
+    let exif = if source.member().is_some() {
+        let mut bytes = Vec::new();
+        with_source(source, |file| Ok(file.read_to_end(&mut bytes)?))?;
+        Reader::new().read_from_container(&mut Cursor::new(bytes))
+    } else {
+        Reader::new().read_from_container(&mut BufReader::new(File::open(source.path())?))
+    };
+    let exif = match exif {
+        Ok(exif) => exif,
+        Err(exif::Error::Io(e)) if e.kind() != std::io::ErrorKind::UnexpectedEof => {
+            return Err(e.into())
+        }
+        // Not an image, or an image without EXIF data.
+        Err(_) => return Ok(None),
+    };
+    let date_time = match exif.get_field(Tag::DateTimeOriginal, In::PRIMARY) {
+        Some(field) => match &field.value {
+            Value::Ascii(values) if !values.is_empty() => {
+                exif::DateTime::from_ascii(&values[0]).ok()
+            }
+            _ => None,
+        },
+        None => None,
+    };
+    Ok(date_time.and_then(|date_time| {
+        let naive = NaiveDate::from_ymd_opt(
+            date_time.year as i32,
+            date_time.month as u32,
+            date_time.day as u32,
+        )?
+        .and_hms_opt(
+            date_time.hour as u32,
+            date_time.minute as u32,
+            date_time.second as u32,
+        )?;
+        Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .map(|date_time| date_time.timestamp())
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::Timestamp;
+
+    // Little endian TIFF with an EXIF IFD holding only DateTimeOriginal.
+    fn tiff_with_date(date: &[u8; 20]) -> Vec<u8> {
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        for (tag, kind, count, value) in [(0x8769u16, 4u16, 1u32, 26u32), (0x9003, 2, 20, 44)] {
+            tiff.extend_from_slice(&1u16.to_le_bytes());
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&kind.to_le_bytes());
+            tiff.extend_from_slice(&count.to_le_bytes());
+            tiff.extend_from_slice(&value.to_le_bytes());
+            tiff.extend_from_slice(&0u32.to_le_bytes());
+        }
+        tiff.extend_from_slice(date);
+        tiff
+    }
+
+    #[test]
+    fn test_date_of_jpeg() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.jpg");
+        let tiff = tiff_with_date(b"2017:08:09 10:11:12\0");
+        let mut jpeg = b"\xFF\xD8\xFF\xE1".to_vec();
+        jpeg.extend_from_slice(&(tiff.len() as u16 + 8).to_be_bytes());
+        jpeg.extend_from_slice(b"Exif\0\0");
+        jpeg.extend_from_slice(&tiff);
+        jpeg.extend_from_slice(b"\xFF\xD9");
+        std::fs::write(&path, jpeg).unwrap();
+        let expected = "2017-08-09 10:11:12".parse::<Timestamp>().unwrap().0;
+        assert_eq!(exif_date(&path).unwrap(), Some(expected));
+    }
+
+    #[test]
+    fn test_files_without_date() {
+        let dir = tempfile::tempdir().unwrap();
+        let blank = dir.path().join("a.tif");
+        std::fs::write(&blank, tiff_with_date(b"    :  :     :  :  \0")).unwrap();
+        assert_eq!(exif_date(&blank).unwrap(), None);
+        let text = dir.path().join("a.txt");
+        std::fs::write(&text, "hello").unwrap();
+        assert_eq!(exif_date(&text).unwrap(), None);
+    }
+}
@@ -0,0 +1,259 @@
+use crate::internals::Record;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::path::PathBuf;
+use tempfile::NamedTempFile;
+
+#[derive(Copy, Clone, Debug)]
+pub enum SortKey {
+    Index,
+    Group,
+}
+
+// A record along with its position in the input, so the input order can be
+// restored after sorting by group.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Entry {
+    pub index: u64,
+    pub group: String,
+    // Which of the grouping keys produced this entry.
+    pub grouping: usize,
+    pub path: String,
+    pub size: u64,
+    pub hash: String,
+    pub modified: Option<i64>,
+    pub dir_modified: Option<i64>,
+    pub in_archive: bool,
+    pub whole_hash: bool,
+    // Set while looking for duplicates, when another entry has the same key.
+    pub duplicated: bool,
+}
+
+impl Entry {
+    pub fn new(index: u64, group: String, grouping: usize, record: Record) -> Self {
+        Entry {
+            index,
+            group,
+            grouping,
+            path: record.path,
+            size: record.size,
+            hash: record.hash,
+            modified: record.modified,
+            dir_modified: record.dir_modified,
+            in_archive: record.in_archive,
+            whole_hash: record.whole_hash,
+            duplicated: false,
+        }
+    }
+
+    pub fn into_record(self) -> Record {
+        Record {
+            path: self.path,
+            size: self.size,
+            hash: self.hash,
+            modified: self.modified,
+            dir_modified: self.dir_modified,
+            in_archive: self.in_archive,
+            whole_hash: self.whole_hash,
+        }
+    }
+
+    // Ties are broken by index, so entries with the same key keep their
+    // input order.
+    pub fn compare(&self, other: &Entry, key: SortKey) -> Ordering {
+        match key {
+            SortKey::Index => Ordering::Equal,
+            SortKey::Group => self.group.cmp(&other.group),
+        }
+        .then(self.index.cmp(&other.index))
+    }
+}
+
+// Sorts more entries than fit in memory: every `capacity` entries the buffer
+// is sorted and written to a temporary file, and those files are merged at
+// the end.
+pub struct ExternalSort {
+    key: SortKey,
+    capacity: usize,
+    temp_dir: Option<PathBuf>,
+    buffer: Vec<Entry>,
+    runs: Vec<NamedTempFile>,
+}
+
+impl ExternalSort {
+    pub fn new(key: SortKey, capacity: usize, temp_dir: Option<PathBuf>) -> Self {
+        ExternalSort {
+            key,
+            capacity: capacity.max(1),
+            temp_dir,
+            buffer: Vec::new(),
+            runs: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, entry: Entry) -> Result<()> {
+        self.buffer.push(entry);
+        if self.buffer.len() >= self.capacity {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<SortedEntries> {
+        let key = self.key;
+        self.buffer.sort_by(|a, b| a.compare(b, key));
+        let mut sources = vec![Source::Memory(std::mem::take(&mut self.buffer).into_iter())];
+        for run in self.runs.iter() {
+            let reader = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .from_reader(run.reopen()?);
+            sources.push(Source::File(reader.into_deserialize()));
+        }
+        let mut sorted = SortedEntries {
+            key,
+            sources,
+            heap: BinaryHeap::new(),
+            _runs: self.runs,
+        };
+        for source in 0..sorted.sources.len() {
+            sorted.fill(source)?;
+        }
+        Ok(sorted)
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        let key = self.key;
+        self.buffer.sort_by(|a, b| a.compare(b, key));
+        let run = match &self.temp_dir {
+            Some(dir) => NamedTempFile::new_in(dir)?,
+            None => NamedTempFile::new()?,
+        };
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(run.reopen()?);
+        for entry in self.buffer.drain(..) {
+            writer.serialize(entry)?;
+        }
+        writer.flush()?;
+        self.runs.push(run);
+        Ok(())
+    }
+}
+
+enum Source {
+    Memory(std::vec::IntoIter<Entry>),
+    File(csv::DeserializeRecordsIntoIter<File, Entry>),
+}
+
+struct HeapItem {
+    entry: Entry,
+    source: usize,
+    key: SortKey,
+}
+
+impl Ord for HeapItem {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.entry.compare(&other.entry, self.key)
+    }
+}
+
+impl PartialOrd for HeapItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapItem {}
+
+pub struct SortedEntries {
+    key: SortKey,
+    sources: Vec<Source>,
+    heap: BinaryHeap<Reverse<HeapItem>>,
+    // Keeps the temporary files alive until the merge is done.
+    _runs: Vec<NamedTempFile>,
+}
+
+impl SortedEntries {
+    fn fill(&mut self, source: usize) -> Result<()> {
+        let next = match &mut self.sources[source] {
+            Source::Memory(entries) => entries.next(),
+            Source::File(entries) => entries.next().transpose()?,
+        };
+        if let Some(entry) = next {
+            self.heap.push(Reverse(HeapItem {
+                entry,
+                source,
+                key: self.key,
+            }));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for SortedEntries {
+    type Item = Result<Entry>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse(item) = self.heap.pop()?;
+        if let Err(e) = self.fill(item.source) {
+            return Some(Err(e));
+        }
+        Some(Ok(item.entry))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(index: u64, size: u64) -> Entry {
+        Entry {
+            index,
+            group: size.to_string(),
+            grouping: 0,
+            path: format!("/{}", index),
+            size,
+            hash: "NULL".into(),
+            modified: None,
+            dir_modified: Some(1),
+            in_archive: false,
+            whole_hash: false,
+            duplicated: false,
+        }
+    }
+
+    #[test]
+    fn test_sort_spilling_to_temp_files() {
+        let mut sort = ExternalSort::new(SortKey::Group, 2, None);
+        for (index, size) in [5, 3, 9, 3, 1].iter().enumerate() {
+            sort.push(entry(index as u64, *size)).unwrap();
+        }
+        assert_eq!(sort.runs.len(), 2);
+        let actual: Vec<(u64, u64)> = sort
+            .finish()
+            .unwrap()
+            .map(|entry| entry.map(|entry| (entry.size, entry.index)))
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(actual, vec![(1, 4), (3, 1), (3, 3), (5, 0), (9, 2)]);
+    }
+
+    #[test]
+    fn test_entries_survive_the_temp_files() {
+        let mut sort = ExternalSort::new(SortKey::Index, 1, None);
+        sort.push(entry(7, 42)).unwrap();
+        let actual = sort.finish().unwrap().next().unwrap().unwrap();
+        assert_eq!(actual.index, 7);
+        assert_eq!(actual.path, "/7");
+        assert_eq!(actual.modified, None);
+        assert_eq!(actual.dir_modified, Some(1));
+    }
+}
@@ -0,0 +1,387 @@
+use crate::common::FileCategory;
+use crate::internals::{with_source, AsSource};
+use anyhow::Result;
+use std::io::Read;
+
+// Enough bytes to see every signature below, including the tar header, and
+// two MPEG audio frames.
+const HEADER_SIZE: usize = 4096;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FileType {
+    pub mime: &'static str,
+    pub category: FileCategory,
+}
+
+// Signatures found at a fixed offset from the start of the file.
+const SIGNATURES: &[(usize, &[u8], &str, FileCategory)] = &[
+    (0, b"\xFF\xD8\xFF", "image/jpeg", FileCategory::Image),
+    (0, b"\x89PNG\r\n\x1A\n", "image/png", FileCategory::Image),
+    (0, b"GIF87a", "image/gif", FileCategory::Image),
+    (0, b"GIF89a", "image/gif", FileCategory::Image),
+    (0, b"8BPS", "image/vnd.adobe.photoshop", FileCategory::Image),
+    (
+        0,
+        b"FUJIFILMCCD-RAW",
+        "image/x-fuji-raf",
+        FileCategory::Image,
+    ),
+    (0, b"IIRO", "image/x-olympus-orf", FileCategory::Image),
+    (0, b"IIU\0", "image/x-panasonic-rw2", FileCategory::Image),
+    // Most camera raw formats (CR2, NEF, ARW, DNG) are TIFF files as well.
+    (0, b"II*\0", "image/tiff", FileCategory::Image),
+    (0, b"MM\0*", "image/tiff", FileCategory::Image),
+    (0, b"\0\0\x01\0", "image/x-icon", FileCategory::Image),
+    (0, b"ID3", "audio/mpeg", FileCategory::Audio),
+    (0, b"fLaC", "audio/flac", FileCategory::Audio),
+    (0, b"OggS", "audio/ogg", FileCategory::Audio),
+    (0, b"MThd", "audio/midi", FileCategory::Audio),
+    (0, b"#!AMR", "audio/amr", FileCategory::Audio),
+    (
+        0,
+        b"\x1A\x45\xDF\xA3",
+        "video/x-matroska",
+        FileCategory::Video,
+    ),
+    (0, b"FLV\x01", "video/x-flv", FileCategory::Video),
+    (
+        0,
+        b"\x30\x26\xB2\x75\x8E\x66\xCF\x11",
+        "video/x-ms-asf",
+        FileCategory::Video,
+    ),
+    (0, b"\0\0\x01\xBA", "video/mpeg", FileCategory::Video),
+    (0, b"\0\0\x01\xB3", "video/mpeg", FileCategory::Video),
+    (0, b"%PDF-", "application/pdf", FileCategory::Document),
+    (0, b"%!PS", "application/postscript", FileCategory::Document),
+    (0, b"{\\rtf", "application/rtf", FileCategory::Document),
+    (0, b"AT&TFORM", "image/vnd.djvu", FileCategory::Document),
+    // Legacy Office files (doc, xls, ppt) use this container.
+    (
+        0,
+        b"\xD0\xCF\x11\xE0\xA1\xB1\x1A\xE1",
+        "application/x-ole-storage",
+        FileCategory::Document,
+    ),
+    (
+        0,
+        b"7z\xBC\xAF\x27\x1C",
+        "application/x-7z-compressed",
+        FileCategory::Archive,
+    ),
+    (
+        0,
+        b"Rar!\x1A\x07",
+        "application/vnd.rar",
+        FileCategory::Archive,
+    ),
+    (0, b"\x1F\x8B", "application/gzip", FileCategory::Archive),
+    (0, b"BZh", "application/x-bzip2", FileCategory::Archive),
+    (0, b"\xFD7zXZ\0", "application/x-xz", FileCategory::Archive),
+    (
+        0,
+        b"\x28\xB5\x2F\xFD",
+        "application/zstd",
+        FileCategory::Archive,
+    ),
+    (
+        0,
+        b"MSCF",
+        "application/vnd.ms-cab-compressed",
+        FileCategory::Archive,
+    ),
+    (257, b"ustar", "application/x-tar", FileCategory::Archive),
+];
+
+pub fn sniff_file_type(source: &(impl AsSource + ?Sized)) -> Result<Option<FileType>> {
+    with_source(source, sniff_reader)
+}
+
+pub fn sniff_reader(file: &mut dyn Read) -> Result<Option<FileType>> {
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    file.take(HEADER_SIZE as u64).read_to_end(&mut header)?;
+    Ok(sniff(&header))
+}
+
+fn sniff(header: &[u8]) -> Option<FileType> {
+    let file_type = |mime, category| Some(FileType { mime, category });
+    let at = |offset: usize, signature: &[u8]| {
+        header.len() >= offset + signature.len()
+            && &header[offset..offset + signature.len()] == signature
+    };
+    if at(0, b"RIFF") && header.len() >= 12 {
+        return match &header[8..12] {
+            b"WEBP" => file_type("image/webp", FileCategory::Image),
+            b"WAVE" => file_type("audio/wav", FileCategory::Audio),
+            b"AVI " => file_type("video/x-msvideo", FileCategory::Video),
+            _ => None,
+        };
+    }
+    if at(0, b"FORM") && (at(8, b"AIFF") || at(8, b"AIFC")) {
+        return file_type("audio/aiff", FileCategory::Audio);
+    }
+    if at(4, b"ftyp") && header.len() >= 12 {
+        return sniff_iso_media(&header[8..12]);
+    }
+    if at(0, b"PK\x03\x04") || at(0, b"PK\x05\x06") {
+        return sniff_zip(header);
+    }
+    if let Some(&(_, _, mime, category)) = SIGNATURES
+        .iter()
+        .find(|(offset, signature, _, _)| at(*offset, signature))
+    {
+        return file_type(mime, category);
+    }
+    // The size of the header that follows tells bitmaps from text.
+    if at(0, b"BM")
+        && header.len() >= 18
+        && [12, 40, 52, 56, 64, 108, 124].contains(&u32::from_le_bytes([
+            header[14], header[15], header[16], header[17],
+        ]))
+    {
+        return file_type("image/bmp", FileCategory::Image);
+    }
+    if let Some(file_type) = sniff_audio_frames(header) {
+        return Some(file_type);
+    }
+    if at(0, b"\x47") && header.len() > 188 && header[188] == 0x47 {
+        return file_type("video/mp2t", FileCategory::Video);
+    }
+    if is_svg(header) {
+        return file_type("image/svg+xml", FileCategory::Image);
+    }
+    None
+}
+
+// MPEG audio and ADTS AAC frames start with 11 set bits, as does the UTF-16
+// byte order mark, so the frame header must be valid and followed by another
+// frame.
+fn sniff_audio_frames(header: &[u8]) -> Option<FileType> {
+    let (length, mime) = if header.get(1)? & 0x06 == 0 {
+        (adts_frame_length(header)?, "audio/aac")
+    } else {
+        (mpeg_frame_length(header)?, "audio/mpeg")
+    };
+    let next_frame = match header.get(length..length + 2) {
+        Some(next) => next[0] == 0xFF && next[1] & 0xFE == header[1] & 0xFE,
+        // A file holding a single frame.
+        None => length == header.len(),
+    };
+    if next_frame {
+        Some(FileType {
+            mime,
+            category: FileCategory::Audio,
+        })
+    } else {
+        None
+    }
+}
+
+fn mpeg_frame_length(header: &[u8]) -> Option<usize> {
+    const BITRATES: [[u32; 14]; 5] = [
+        [
+            32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+        ],
+        [
+            32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+        ],
+        [
+            32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+        ],
+        [
+            32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+        ],
+        [8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+    ];
+    const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+    let header = header.get(..3)?;
+    if header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+        return None;
+    }
+    // Version: 0 is MPEG 2.5, 2 MPEG 2 and 3 MPEG 1. Layer: 1 is III, 3 is I.
+    let version = (header[1] >> 3) & 0x03;
+    let layer = (header[1] >> 1) & 0x03;
+    let bitrate_index = (header[2] >> 4) as usize;
+    let sample_rate_index = ((header[2] >> 2) & 0x03) as usize;
+    let padding = ((header[2] >> 1) & 0x01) as u32;
+    // Free bitrates don't give the frame length.
+    if version == 1 || layer == 0 || bitrate_index == 0 || bitrate_index == 15 {
+        return None;
+    }
+    let bitrates = match (version, layer) {
+        (3, 3) => &BITRATES[0],
+        (3, 2) => &BITRATES[1],
+        (3, _) => &BITRATES[2],
+        (_, 3) => &BITRATES[3],
+        _ => &BITRATES[4],
+    };
+    let bitrate = bitrates[bitrate_index - 1] * 1000;
+    let sample_rate = *SAMPLE_RATES.get(sample_rate_index)? >> (3 - version.max(1));
+    let length = match layer {
+        3 => (12 * bitrate / sample_rate + padding) * 4,
+        1 if version != 3 => 72 * bitrate / sample_rate + padding,
+        _ => 144 * bitrate / sample_rate + padding,
+    };
+    Some(length as usize)
+}
+
+fn adts_frame_length(header: &[u8]) -> Option<usize> {
+    let header = header.get(..7)?;
+    if header[0] != 0xFF || header[1] & 0xF6 != 0xF0 || (header[2] >> 2) & 0x0F > 12 {
+        return None;
+    }
+    let length = ((header[3] as usize & 0x03) << 11)
+        | ((header[4] as usize) << 3)
+        | (header[5] as usize >> 5);
+    if length < 7 {
+        return None;
+    }
+    Some(length)
+}
+
+// MP4, QuickTime, HEIF and friends share the same container, the brand
+// tells them apart.
+fn sniff_iso_media(brand: &[u8]) -> Option<FileType> {
+    let (mime, category) = match brand {
+        b"heic" | b"heix" | b"hevc" | b"heim" | b"heis" => ("image/heic", FileCategory::Image),
+        b"mif1" | b"msf1" => ("image/heif", FileCategory::Image),
+        b"avif" | b"avis" => ("image/avif", FileCategory::Image),
+        b"crx " => ("image/x-canon-cr3", FileCategory::Image),
+        b"M4A " | b"M4B " | b"M4P " => ("audio/mp4", FileCategory::Audio),
+        b"qt  " => ("video/quicktime", FileCategory::Video),
+        _ if brand.starts_with(b"3g") => ("video/3gpp", FileCategory::Video),
+        _ => ("video/mp4", FileCategory::Video),
+    };
+    Some(FileType { mime, category })
+}
+
+// Office and OpenDocument files are zip files too, they are recognized by
+// the name of their first member.
+fn sniff_zip(header: &[u8]) -> Option<FileType> {
+    let first_member = header.get(30..).unwrap_or_default();
+    let (mime, category) = if first_member.starts_with(b"mimetypeapplication/epub+zip") {
+        ("application/epub+zip", FileCategory::Document)
+    } else if first_member.starts_with(b"mimetypeapplication/vnd.oasis.opendocument") {
+        ("application/vnd.oasis.opendocument", FileCategory::Document)
+    } else if first_member.starts_with(b"[Content_Types].xml")
+        || first_member.starts_with(b"_rels/.rels")
+        || first_member.starts_with(b"word/")
+        || first_member.starts_with(b"xl/")
+        || first_member.starts_with(b"ppt/")
+        || first_member.starts_with(b"docProps/")
+    {
+        (
+            "application/vnd.openxmlformats-officedocument",
+            FileCategory::Document,
+        )
+    } else {
+        ("application/zip", FileCategory::Archive)
+    };
+    Some(FileType { mime, category })
+}
+
+fn is_svg(header: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(header);
+    let text = text.trim_start_matches('\u{FEFF}').trim_start();
+    (text.starts_with("<?xml") || text.starts_with("<svg") || text.starts_with("<!DOCTYPE svg"))
+        && text.contains("<svg")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn category(header: &[u8]) -> Option<FileCategory> {
+        sniff(header).map(|file_type| file_type.category)
+    }
+
+    #[test]
+    fn test_jpeg_without_extension_is_image() {
+        assert_eq!(
+            category(b"\xFF\xD8\xFF\xE1\0\x18Exif\0\0"),
+            Some(FileCategory::Image)
+        );
+    }
+
+    #[test]
+    fn test_riff_is_told_apart_by_form_type() {
+        assert_eq!(category(b"RIFF\0\0\0\0WEBPVP8 "), Some(FileCategory::Image));
+        assert_eq!(category(b"RIFF\0\0\0\0WAVEfmt "), Some(FileCategory::Audio));
+        assert_eq!(category(b"RIFF\0\0\0\0AVI LIST"), Some(FileCategory::Video));
+    }
+
+    #[test]
+    fn test_iso_media_is_told_apart_by_brand() {
+        assert_eq!(
+            category(b"\0\0\0\x18ftypheic\0\0\0\0"),
+            Some(FileCategory::Image)
+        );
+        assert_eq!(
+            category(b"\0\0\0\x18ftypM4A \0\0\0\0"),
+            Some(FileCategory::Audio)
+        );
+        assert_eq!(
+            category(b"\0\0\0\x18ftypisom\0\0\0\0"),
+            Some(FileCategory::Video)
+        );
+    }
+
+    #[test]
+    fn test_office_zip_is_document() {
+        let mut header = b"PK\x03\x04".to_vec();
+        header.resize(30, 0);
+        header.extend_from_slice(b"[Content_Types].xml");
+        assert_eq!(category(&header), Some(FileCategory::Document));
+        header.truncate(30);
+        header.extend_from_slice(b"photos/a.jpg");
+        assert_eq!(category(&header), Some(FileCategory::Archive));
+    }
+
+    #[test]
+    fn test_svg_is_image() {
+        let header = b"<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\">";
+        assert_eq!(category(header), Some(FileCategory::Image));
+    }
+
+    fn frames(frame: &[u8], length: usize) -> Vec<u8> {
+        let mut header = frame.to_vec();
+        header.resize(length, 0);
+        header.extend_from_slice(frame);
+        header
+    }
+
+    #[test]
+    fn test_audio_frames() {
+        // MPEG 1 layer III, 128 kbit/s, 44.1 kHz
+        let mp3 = sniff(&frames(b"\xFF\xFB\x90\x00", 417)).unwrap();
+        assert_eq!(mp3.mime, "audio/mpeg");
+        // AAC LC, 44.1 kHz, frames of 371 bytes
+        let aac = sniff(&frames(b"\xFF\xF1\x50\x80\x2E\x7F\xFC", 371)).unwrap();
+        assert_eq!(aac.mime, "audio/aac");
+        assert_eq!(category(&frames(b"\xFF\xFB\x90\x00", 400)), None);
+    }
+
+    #[test]
+    fn test_utf16_text_is_not_audio() {
+        let text: Vec<u8> = "\u{FEFF}some text, not audio at all"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        assert_eq!(category(&text), None);
+    }
+
+    #[test]
+    fn test_bitmap_needs_its_header_size() {
+        let mut header = b"BM".to_vec();
+        header.resize(14, 0);
+        header.extend_from_slice(&40u32.to_le_bytes());
+        assert_eq!(category(&header), Some(FileCategory::Image));
+        assert_eq!(category(b"BMW is a car brand"), None);
+    }
+
+    #[test]
+    fn test_plain_text_is_unknown() {
+        assert_eq!(category(b"hello world"), None);
+        assert_eq!(category(b""), None);
+    }
+}
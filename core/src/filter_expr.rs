@@ -0,0 +1,536 @@
+use crate::common::{ByteSize, Timestamp};
+use crate::filter_paths::FilterPath;
+use crate::internals::Record;
+use regex::Regex;
+
+// Small filter language evaluated against every record, for example:
+//   ext in [jpg, png] and size > 10KB and not path ~ "/Temp/"
+//
+// Fields: path, name, ext (lowercase, without dot), hash, size, modified.
+// Dates are local, like 2019-06-15 or "2019-06-15 18:30", or relative like 30d.
+// Operators: = != < <= > >= ~ !~ in starts_with ends_with contains, and the
+// case insensitive istarts_with iends_with icontains.
+// Conditions are combined with and, or, not and parentheses.
+#[derive(Debug, Clone)]
+pub enum FilterExpr {
+    And(Box<FilterExpr>, Box<FilterExpr>),
+    Or(Box<FilterExpr>, Box<FilterExpr>),
+    Not(Box<FilterExpr>),
+    Condition(Condition),
+}
+
+#[derive(Debug, Clone)]
+pub enum Condition {
+    Text(Field, TextOp, FilterPath),
+    Equals(Field, String),
+    In(Field, Vec<String>),
+    Regex(Field, Regex),
+    Size(CmpOp, u64),
+    Modified(CmpOp, i64),
+    Const(bool),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Field {
+    Path,
+    Name,
+    Ext,
+    Hash,
+    Size,
+    Modified,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum TextOp {
+    StartsWith,
+    EndsWith,
+    Contains,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl FilterExpr {
+    pub fn and(self, other: FilterExpr) -> FilterExpr {
+        FilterExpr::And(Box::new(self), Box::new(other))
+    }
+
+    pub fn or(self, other: FilterExpr) -> FilterExpr {
+        FilterExpr::Or(Box::new(self), Box::new(other))
+    }
+
+    pub fn negate(self) -> FilterExpr {
+        FilterExpr::Not(Box::new(self))
+    }
+
+    pub fn all(exprs: impl IntoIterator<Item = FilterExpr>) -> FilterExpr {
+        exprs
+            .into_iter()
+            .reduce(FilterExpr::and)
+            .unwrap_or(FilterExpr::Condition(Condition::Const(true)))
+    }
+
+    pub fn any(exprs: impl IntoIterator<Item = FilterExpr>) -> FilterExpr {
+        exprs
+            .into_iter()
+            .reduce(FilterExpr::or)
+            .unwrap_or(FilterExpr::Condition(Condition::Const(false)))
+    }
+
+    pub(crate) fn uses_modified(&self) -> bool {
+        match self {
+            FilterExpr::And(a, b) | FilterExpr::Or(a, b) => a.uses_modified() || b.uses_modified(),
+            FilterExpr::Not(a) => a.uses_modified(),
+            FilterExpr::Condition(condition) => matches!(condition, Condition::Modified(..)),
+        }
+    }
+
+    pub(crate) fn matches(&self, record: &Record) -> bool {
+        match self {
+            FilterExpr::And(a, b) => a.matches(record) && b.matches(record),
+            FilterExpr::Or(a, b) => a.matches(record) || b.matches(record),
+            FilterExpr::Not(a) => !a.matches(record),
+            FilterExpr::Condition(condition) => condition.matches(record),
+        }
+    }
+}
+
+impl Condition {
+    fn matches(&self, record: &Record) -> bool {
+        match self {
+            Condition::Text(field, op, pattern) => {
+                let text = field.text(record);
+                match op {
+                    TextOp::StartsWith => pattern.starts_with(&text),
+                    TextOp::EndsWith => pattern.ends_with(&text),
+                    TextOp::Contains => pattern.contains(&text),
+                }
+            }
+            Condition::Equals(field, value) => field.text(record) == *value,
+            Condition::In(field, values) => values.contains(&field.text(record)),
+            Condition::Regex(field, regex) => regex.is_match(&field.text(record)),
+            Condition::Size(op, value) => op.compare(record.size, *value),
+            Condition::Modified(op, value) => record
+                .modified
+                .is_some_and(|modified| op.compare(modified, *value)),
+            Condition::Const(value) => *value,
+        }
+    }
+}
+
+impl Field {
+    fn text(self, record: &Record) -> String {
+        match self {
+            Field::Path => record.text_path().into_owned(),
+            Field::Name => file_name(&record.text_path()).into(),
+            Field::Ext => {
+                let path = record.text_path();
+                let name = file_name(&path);
+                match name.rfind('.') {
+                    Some(dot) if dot > 0 => name[dot + 1..].to_lowercase(),
+                    _ => String::new(),
+                }
+            }
+            Field::Hash => record.hash.clone(),
+            Field::Size => record.size.to_string(),
+            Field::Modified => record
+                .modified
+                .map(|modified| modified.to_string())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl CmpOp {
+    fn compare<T: PartialOrd>(self, a: T, b: T) -> bool {
+        match self {
+            CmpOp::Eq => a == b,
+            CmpOp::Ne => a != b,
+            CmpOp::Lt => a < b,
+            CmpOp::Le => a <= b,
+            CmpOp::Gt => a > b,
+            CmpOp::Ge => a >= b,
+        }
+    }
+}
+
+fn file_name(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(slash) => &path[slash + 1..],
+        None => path,
+    }
+}
+
+impl std::str::FromStr for FilterExpr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            position: 0,
+        };
+        let expr = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(format!("Unexpected '{}' in filter expression.", token)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Symbol(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word),
+            Token::Quoted(text) => write!(f, "\"{}\"", text),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+const SYMBOLS: &[&str] = &[
+    "!=", "!~", "<=", ">=", "=", "<", ">", "~", "(", ")", "[", "]", ",",
+];
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = s;
+    loop {
+        rest = rest.trim_start();
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => return Ok(tokens),
+        };
+        if c == '"' {
+            let mut text = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i + 2,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c)) => text.push(c),
+                        None => return Err("Unterminated string in filter expression.".into()),
+                    },
+                    Some((_, c)) => text.push(c),
+                    None => return Err("Unterminated string in filter expression.".into()),
+                }
+            };
+            tokens.push(Token::Quoted(text));
+            rest = &rest[end..];
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else {
+            let end = rest
+                .find(|c: char| c.is_whitespace() || c == '"' || "!=<>~()[],".contains(c))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                return Err(format!("Unexpected '{}' in filter expression.", c));
+            }
+            tokens.push(Token::Word(rest[..end].into()));
+            rest = &rest[end..];
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| "Unexpected end of filter expression.".to_string())?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn next_is_word(&mut self, word: &str) -> bool {
+        if let Some(Token::Word(next)) = self.peek() {
+            if next == word {
+                self.position += 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        match self.next()? {
+            Token::Symbol(next) if next == symbol => Ok(()),
+            token => Err(format!("Expected '{}' but found '{}'.", symbol, token)),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<FilterExpr, String> {
+        let mut expr = self.parse_and()?;
+        while self.next_is_word("or") {
+            expr = expr.or(self.parse_and()?);
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<FilterExpr, String> {
+        let mut expr = self.parse_not()?;
+        while self.next_is_word("and") {
+            expr = expr.and(self.parse_not()?);
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<FilterExpr, String> {
+        if self.next_is_word("not") {
+            return Ok(self.parse_not()?.negate());
+        }
+        if let Some(Token::Symbol("(")) = self.peek() {
+            self.position += 1;
+            let expr = self.parse_or()?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        self.parse_condition()
+    }
+
+    fn parse_condition(&mut self) -> Result<FilterExpr, String> {
+        let field = match self.next()? {
+            Token::Word(word) => match word.as_str() {
+                "path" => Field::Path,
+                "name" => Field::Name,
+                "ext" => Field::Ext,
+                "hash" => Field::Hash,
+                "size" => Field::Size,
+                "modified" => Field::Modified,
+                _ => {
+                    return Err(format!(
+                        "No field named '{}', try these instead: path, name, ext, hash, size, modified.",
+                        word
+                    ))
+                }
+            },
+            token => return Err(format!("Expected a field but found '{}'.", token)),
+        };
+        let op = self.next()?;
+        if field == Field::Size {
+            return self.parse_size_condition(op);
+        }
+        if field == Field::Modified {
+            return self.parse_modified_condition(op);
+        }
+        let condition = match op {
+            Token::Symbol("=") => Condition::Equals(field, self.parse_text(field)?),
+            Token::Symbol("!=") => {
+                return Ok(
+                    FilterExpr::Condition(Condition::Equals(field, self.parse_text(field)?))
+                        .negate(),
+                )
+            }
+            Token::Symbol("~") => Condition::Regex(field, self.parse_regex()?),
+            Token::Symbol("!~") => {
+                return Ok(
+                    FilterExpr::Condition(Condition::Regex(field, self.parse_regex()?)).negate(),
+                )
+            }
+            Token::Word(word) => match word.as_str() {
+                "in" => Condition::In(field, self.parse_list(field)?),
+                "starts_with" | "ends_with" | "contains" | "istarts_with" | "iends_with"
+                | "icontains" => {
+                    let case_insensitive = word.starts_with('i');
+                    let op = match word.trim_start_matches('i') {
+                        "starts_with" => TextOp::StartsWith,
+                        "ends_with" => TextOp::EndsWith,
+                        _ => TextOp::Contains,
+                    };
+                    let text = self.parse_text(field)?;
+                    Condition::Text(field, op, FilterPath::with_case(&text, case_insensitive))
+                }
+                _ => return Err(format!("Unknown operator '{}' in filter expression.", word)),
+            },
+            token => {
+                return Err(format!(
+                    "Unknown operator '{}' in filter expression.",
+                    token
+                ))
+            }
+        };
+        Ok(FilterExpr::Condition(condition))
+    }
+
+    fn parse_size_condition(&mut self, op: Token) -> Result<FilterExpr, String> {
+        let op = parse_cmp_op(op, "size")?;
+        let value = match self.next()? {
+            Token::Word(word) | Token::Quoted(word) => word.parse::<ByteSize>()?.0,
+            token => return Err(format!("Expected a size but found '{}'.", token)),
+        };
+        Ok(FilterExpr::Condition(Condition::Size(op, value)))
+    }
+
+    fn parse_modified_condition(&mut self, op: Token) -> Result<FilterExpr, String> {
+        let op = parse_cmp_op(op, "modified")?;
+        let value = match self.next()? {
+            Token::Word(word) | Token::Quoted(word) => word.parse::<Timestamp>()?.0,
+            token => return Err(format!("Expected a date but found '{}'.", token)),
+        };
+        Ok(FilterExpr::Condition(Condition::Modified(op, value)))
+    }
+
+    fn parse_text(&mut self, field: Field) -> Result<String, String> {
+        match self.next()? {
+            Token::Word(text) | Token::Quoted(text) if field == Field::Ext => {
+                Ok(text.trim_start_matches('.').to_lowercase())
+            }
+            Token::Word(text) | Token::Quoted(text) => Ok(text),
+            token => Err(format!("Expected a value but found '{}'.", token)),
+        }
+    }
+
+    fn parse_regex(&mut self) -> Result<Regex, String> {
+        match self.next()? {
+            Token::Word(text) | Token::Quoted(text) => {
+                Regex::new(&text).map_err(|e| format!("Wrong regex '{}': {}", text, e))
+            }
+            token => Err(format!("Expected a regex but found '{}'.", token)),
+        }
+    }
+
+    fn parse_list(&mut self, field: Field) -> Result<Vec<String>, String> {
+        self.expect("[")?;
+        let mut values = vec![self.parse_text(field)?];
+        loop {
+            match self.next()? {
+                Token::Symbol("]") => return Ok(values),
+                Token::Symbol(",") => values.push(self.parse_text(field)?),
+                token => return Err(format!("Expected ',' or ']' but found '{}'.", token)),
+            }
+        }
+    }
+}
+
+fn parse_cmp_op(op: Token, field: &str) -> Result<CmpOp, String> {
+    match op {
+        Token::Symbol("=") => Ok(CmpOp::Eq),
+        Token::Symbol("!=") => Ok(CmpOp::Ne),
+        Token::Symbol("<") => Ok(CmpOp::Lt),
+        Token::Symbol("<=") => Ok(CmpOp::Le),
+        Token::Symbol(">") => Ok(CmpOp::Gt),
+        Token::Symbol(">=") => Ok(CmpOp::Ge),
+        token => Err(format!(
+            "Operator '{}' can't be used with {}.",
+            token, field
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn matches(expr: &str, path: &str, size: u64) -> bool {
+        let expr: FilterExpr = expr.parse().unwrap();
+        expr.matches(&Record::for_test(path, size, "NULL"))
+    }
+
+    #[test]
+    fn test_ext_in_list_matches() {
+        assert!(matches("ext in [jpg, png]", "/pics/IMG_01.JPG", 1));
+    }
+
+    #[test]
+    fn test_ext_not_in_list_does_not_match() {
+        assert!(!matches("ext in [jpg, png]", "/pics/IMG_01.gif", 1));
+    }
+
+    #[test]
+    fn test_size_with_unit() {
+        assert!(matches("size > 10KB", "/a.jpg", 10_001));
+        assert!(!matches("size > 10KB", "/a.jpg", 10_000));
+        assert!(matches("size >= 1KiB", "/a.jpg", 1024));
+    }
+
+    #[test]
+    fn test_and_not_regex() {
+        let expr = "ext in [jpg,png] and size > 10KB and not path ~ \"/Temp/\"";
+        assert!(matches(expr, "/mnt/c/Pics/a.png", 20_000));
+        assert!(!matches(expr, "/mnt/c/Temp/a.png", 20_000));
+    }
+
+    #[test]
+    fn test_or_binds_weaker_than_and() {
+        let expr = "name = a.mp3 or name = b.mp3 and size > 10";
+        assert!(matches(expr, "/a.mp3", 1));
+        assert!(!matches(expr, "/b.mp3", 1));
+        assert!(!matches(
+            "(name = a.mp3 or name = b.mp3) and size > 10",
+            "/a.mp3",
+            1
+        ));
+    }
+
+    #[test]
+    fn test_case_insensitive_text_operator() {
+        assert!(matches("path icontains \"/dcim/\"", "/sd/DCIM/a.jpg", 1));
+        assert!(!matches("path contains \"/dcim/\"", "/sd/DCIM/a.jpg", 1));
+    }
+
+    #[test]
+    fn test_wrong_field_is_an_error() {
+        assert!("color = red".parse::<FilterExpr>().is_err());
+    }
+
+    #[test]
+    fn test_trailing_tokens_are_an_error() {
+        assert!("size > 1 size".parse::<FilterExpr>().is_err());
+    }
+
+    #[test]
+    fn test_modified_between_dates() {
+        let expr: FilterExpr = "modified >= 2019-06-15 and modified < \"2019-06-16 00:00\""
+            .parse()
+            .unwrap();
+        let day = "2019-06-15".parse::<Timestamp>().unwrap().0;
+        let mut record = Record::for_test("/a.jpg", 1, "NULL");
+        record.modified = Some(day + 12 * 60 * 60);
+        assert!(expr.matches(&record));
+        record.modified = Some(day + 24 * 60 * 60);
+        assert!(!expr.matches(&record));
+        record.modified = None;
+        assert!(!expr.matches(&record));
+    }
+
+    #[test]
+    fn test_relative_date() {
+        let expr: FilterExpr = "modified > 30d".parse().unwrap();
+        let now = chrono::Local::now().timestamp();
+        let record = |modified| Record {
+            modified: Some(modified),
+            ..Record::for_test("/a.jpg", 1, "NULL")
+        };
+        assert!(expr.matches(&record(now - 29 * 24 * 60 * 60)));
+        assert!(!expr.matches(&record(now - 31 * 24 * 60 * 60)));
+    }
+
+    #[test]
+    fn test_wrong_date_is_an_error() {
+        assert!("modified > 2019-13-01".parse::<FilterExpr>().is_err());
+        assert!("modified > 30x".parse::<FilterExpr>().is_err());
+    }
+}
@@ -1,9 +1,15 @@
-use crate::common::Debug;
-use crate::internals::Record;
+use crate::archives::MemberBatch;
+use crate::categories::category_extensions;
+use crate::common::{Debug, FileCategory, GroupKey, SizeFormat};
+use crate::external_sort::{Entry, ExternalSort, SortKey};
+use crate::file_types::{sniff_file_type, sniff_reader, FileType};
+use crate::filter_expr::{CmpOp, Condition, Field, FilterExpr, TextOp};
+use crate::internals::{modified_time, Record, Reporter};
 use anyhow::Result;
 use num_format::{Locale, ToFormattedString};
-use size_format::SizeFormatterSI;
-use std::collections::{HashMap, HashSet};
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+use std::borrow::Cow;
 use std::fs::File;
 use std::path::PathBuf;
 use std::time::Instant;
@@ -14,17 +20,31 @@ pub struct FilterPathsConfig {
     pub target_file: PathBuf,
     pub debug: Debug,
     pub error_log: Option<PathBuf>,
+    pub size_format: SizeFormat,
     pub size_min: u64,
     pub size_max: u64,
-    pub unique_sizes: bool,
-    pub unique_hashes: bool,
+    pub exclude_unique_by: Vec<GroupKey>,
+    pub exclude_duplicated_by: Vec<GroupKey>,
     pub blacklist_path_starts: Vec<FilterPath>,
     pub blacklist_path_ends: Vec<FilterPath>,
     pub blacklist_path_contents: Vec<FilterPath>,
     pub whitelist_path_ends: Vec<FilterPath>,
     pub whitelist_path_contents: Vec<FilterPath>,
+    pub expression: Option<FilterExpr>,
+    pub whitelist_file_types: Vec<FileCategory>,
+    pub categories: Vec<String>,
+    pub categories_file: Option<PathBuf>,
+    pub modified_after: Option<i64>,
+    pub modified_before: Option<i64>,
+    pub sort_buffer: usize,
+    pub temp_dir: Option<PathBuf>,
+    pub rejected_file: Option<PathBuf>,
 }
 
+// Records held in memory by each sort done for the uniqueness filters, the
+// rest is spilled to temporary files.
+pub const DEFAULT_SORT_BUFFER: usize = 1_000_000;
+
 pub fn filter_paths(config: FilterPathsConfig) -> Result<()> {
     println!("FILTER PATHS | config: {:?}", config);
     let now = Instant::now();
@@ -37,22 +57,92 @@ pub fn filter_paths(config: FilterPathsConfig) -> Result<()> {
         ctx.config.target_file
     );
     println!(
-        "Size of all files: {}B",
-        SizeFormatterSI::new(ctx.total_size)
+        "Size of all files: {}",
+        ctx.config.size_format.format(ctx.total_size)
+    );
+    println!(
+        "Rejected {} lines {:?}",
+        ctx.hits
+            .iter()
+            .map(|(_, hits)| hits)
+            .sum::<u64>()
+            .to_formatted_string(&Locale::en),
+        ctx.config.rejected_file
+    );
+    for (rule, hits) in ctx.hits.iter().filter(|(_, hits)| *hits > 0) {
+        println!("    by {}: {}", rule, hits.to_formatted_string(&Locale::en));
+    }
+    println!(
+        "Errors: {} ({:?})",
+        ctx.reporter.error_count().to_formatted_string(&Locale::en),
+        ctx.config.error_log
     );
-    println!("Errors: {} ({:?})", 0, ctx.config.error_log);
     Ok(())
 }
 
 struct Context {
     config: FilterPathsConfig,
+    reporter: Reporter,
+    rules: Vec<Rule>,
+    uses_modified: bool,
+    rejected: Option<csv::Writer<File>>,
+    // Records rejected by each rule, in the same order as `rules`, followed
+    // by the file type and uniqueness filters.
+    hits: Vec<(String, u64)>,
     lines_written: u64,
     total_size: u64,
 }
 
+#[derive(Serialize)]
+struct Duplicated {
+    by_unique_key: bool,
+    by_duplicated_key: bool,
+}
+
+#[derive(Serialize)]
+struct Rejected<'a> {
+    path: &'a str,
+    size: u64,
+    hash: &'a str,
+    modified: Option<i64>,
+    dir_modified: Option<i64>,
+    in_archive: bool,
+    whole_hash: bool,
+    rule: &'a str,
+}
+
+const FILE_TYPE_RULE: usize = 0;
+const UNIQUE_RULE: usize = 1;
+const DUPLICATED_RULE: usize = 2;
+
 impl Context {
     pub fn new(config: FilterPathsConfig) -> Result<Self> {
+        let rules = build_rules(&config)?;
+        let group_rule = |prefix: &str, keys: &[GroupKey]| {
+            let keys: Vec<_> = keys
+                .iter()
+                .map(|key| key.name().replace('-', "_"))
+                .collect();
+            format!("{}_{}", prefix, keys.join("_and_"))
+        };
         Ok(Context {
+            uses_modified: rules.iter().any(|rule| rule.filter.uses_modified()),
+            hits: rules
+                .iter()
+                .map(|rule| rule.name.clone())
+                .chain(vec![
+                    "file_type".into(),
+                    group_rule("unique", &config.exclude_unique_by),
+                    group_rule("duplicated", &config.exclude_duplicated_by),
+                ])
+                .map(|name| (name, 0))
+                .collect(),
+            rules,
+            rejected: match &config.rejected_file {
+                Some(path) => Some(csv::Writer::from_writer(File::create(path)?)),
+                None => None,
+            },
+            reporter: Reporter::new(config.error_log.clone(), config.debug),
             config,
             lines_written: 0,
             total_size: 0,
@@ -62,150 +152,548 @@ impl Context {
     pub fn process(&mut self) -> Result<()> {
         let mut reader = csv::Reader::from_reader(File::open(&self.config.source_file)?);
         let mut writer = csv::Writer::from_writer(File::create(&self.config.target_file)?);
-
-        let mut sizes: HashMap<u64, MapValue> = HashMap::with_capacity(100_000);
-        let mut hashes: HashMap<String, MapValue> = HashMap::with_capacity(100_000);
-
-        let mut dups: HashSet<String> = HashSet::with_capacity(100_000);
-        let mut records: Vec<Record> = Vec::with_capacity(100_000);
-        for record in reader.deserialize() {
-            let record: Record = record?;
-            let path = &record.path;
-            if is_filtered(&self.config, &path, record.size) {
+        // Every record that passed the other filters goes into one sort per
+        // grouping key, the keys of --exclude-unique-by first.
+        let groupings: Vec<GroupKey> = self
+            .config
+            .exclude_unique_by
+            .iter()
+            .chain(self.config.exclude_duplicated_by.iter())
+            .cloned()
+            .collect();
+        let mut sorts: Vec<ExternalSort> = groupings
+            .iter()
+            .map(|_| self.new_sort(SortKey::Group))
+            .collect();
+        let mut members = MemberBatch::default();
+        for (index, record) in reader.deserialize().enumerate() {
+            let mut record: Record = record?;
+            if record.modified.is_none() && self.uses_modified {
+                self.fill_modified(&mut record)?;
+            }
+            let rule = self
+                .rules
+                .iter()
+                .position(|rule| !rule.filter.matches(&record));
+            // Members of one archive that need their file type are sniffed
+            // together, reading the archive only once.
+            if rule.is_none() && !self.config.whitelist_file_types.is_empty() {
+                if let Some(member) = record.source().ok().and_then(|source| source.member) {
+                    if !members.accepts(&member) {
+                        self.sniff_members(&mut members, &mut writer, &groupings, &mut sorts)?;
+                    }
+                    members.push(member, (index, record));
+                    continue;
+                }
+            }
+            self.sniff_members(&mut members, &mut writer, &groupings, &mut sorts)?;
+            if let Some(rule) = rule {
+                self.reject(&record, rule)?;
                 continue;
             }
-            if self.config.unique_sizes {
-                if let Some(other) = sizes.get_mut(&record.size) {
-                    dups.insert(path.into());
-                    if let Some(other_path) = other.path.take() {
-                        dups.insert(other_path);
+            if self.has_file_type(&record)? {
+                self.keep(&mut writer, &groupings, &mut sorts, index, record)?;
+            } else {
+                self.reject(&record, self.rules.len() + FILE_TYPE_RULE)?;
+            }
+        }
+        self.sniff_members(&mut members, &mut writer, &groupings, &mut sorts)?;
+        if groupings.is_empty() {
+            return self.end_writing(writer);
+        }
+        // Then records are sorted back into input order, once per grouping
+        // key, knowing whether they share that key with another record. Every
+        // record goes through, so unique ones are counted as rejected even
+        // without a rejected file.
+        let unique_keys = self.config.exclude_unique_by.len();
+        let mut by_index = self.new_sort(SortKey::Index);
+        for sort in sorts {
+            let mut previous: Option<Entry> = None;
+            for entry in sort.finish()? {
+                let mut entry = entry?;
+                if let Some(mut previous) = previous.take() {
+                    if previous.group == entry.group {
+                        previous.duplicated = true;
+                        entry.duplicated = true;
                     }
-                } else {
-                    sizes.insert(record.size, MapValue::new(path.into()));
+                    by_index.push(previous)?;
                 }
+                previous = Some(entry);
             }
-            if self.config.unique_hashes {
-                let hash = &record.hash;
-                if let Some(other) = hashes.get_mut(hash) {
-                    dups.insert(path.into());
-                    if let Some(other_path) = other.path.take() {
-                        dups.insert(other_path);
+            if let Some(previous) = previous {
+                by_index.push(previous)?;
+            }
+        }
+        let mut current: Option<(Entry, Duplicated)> = None;
+        for entry in by_index.finish()? {
+            let entry = entry?;
+            let duplicated = Duplicated {
+                by_unique_key: entry.duplicated && entry.grouping < unique_keys,
+                by_duplicated_key: entry.duplicated && entry.grouping >= unique_keys,
+            };
+            match &mut current {
+                Some((current, current_duplicated)) if current.index == entry.index => {
+                    current_duplicated.by_unique_key |= duplicated.by_unique_key;
+                    current_duplicated.by_duplicated_key |= duplicated.by_duplicated_key;
+                }
+                _ => {
+                    if let Some((previous, duplicated)) = current.replace((entry, duplicated)) {
+                        self.write_entry(&mut writer, previous, duplicated)?;
                     }
-                } else {
-                    hashes.insert(hash.clone(), MapValue::new(path.into()));
                 }
             }
-            records.push(record);
         }
-        for record in records {
-            if (self.config.unique_hashes || self.config.unique_sizes)
-                && !dups.contains(&record.path)
-            {
-                continue;
+        if let Some((previous, duplicated)) = current {
+            self.write_entry(&mut writer, previous, duplicated)?;
+        }
+        self.end_writing(writer)
+    }
+
+    fn keep(
+        &mut self,
+        writer: &mut csv::Writer<File>,
+        groupings: &[GroupKey],
+        sorts: &mut [ExternalSort],
+        index: usize,
+        record: Record,
+    ) -> Result<()> {
+        if groupings.is_empty() {
+            return self.write_record(writer, record);
+        }
+        for (grouping, key) in groupings.iter().enumerate() {
+            let group = group_of(*key, &record);
+            let entry = Entry::new(index as u64, group, grouping, record.clone());
+            sorts[grouping].push(entry)?;
+        }
+        Ok(())
+    }
+
+    fn sniff_members(
+        &mut self,
+        members: &mut MemberBatch<(usize, Record)>,
+        writer: &mut csv::Writer<File>,
+        groupings: &[GroupKey],
+        sorts: &mut [ExternalSort],
+    ) -> Result<()> {
+        for ((index, record), file_type) in members.read(|_, reader| sniff_reader(reader)) {
+            if self.matches_file_type(&record, file_type)? {
+                self.keep(writer, groupings, sorts, index, record)?;
+            } else {
+                self.reject(&record, self.rules.len() + FILE_TYPE_RULE)?;
             }
-            self.total_size += record.size;
+        }
+        Ok(())
+    }
+
+    fn new_sort(&self, key: SortKey) -> ExternalSort {
+        ExternalSort::new(key, self.config.sort_buffer, self.config.temp_dir.clone())
+    }
+
+    fn write_entry(
+        &mut self,
+        writer: &mut csv::Writer<File>,
+        entry: Entry,
+        duplicated: Duplicated,
+    ) -> Result<()> {
+        let record = entry.into_record();
+        if !self.config.exclude_unique_by.is_empty() && !duplicated.by_unique_key {
+            self.reject(&record, self.rules.len() + UNIQUE_RULE)
+        } else if duplicated.by_duplicated_key {
+            self.reject(&record, self.rules.len() + DUPLICATED_RULE)
+        } else {
+            self.write_record(writer, record)
+        }
+    }
+
+    fn reject(&mut self, record: &Record, rule: usize) -> Result<()> {
+        let (name, hits) = &mut self.hits[rule];
+        *hits += 1;
+        if let Some(rejected) = &mut self.rejected {
+            rejected.serialize(Rejected {
+                path: &record.path,
+                size: record.size,
+                hash: &record.hash,
+                modified: record.modified,
+                dir_modified: record.dir_modified,
+                in_archive: record.in_archive,
+                whole_hash: record.whole_hash,
+                rule: name,
+            })?;
+        }
+        Ok(())
+    }
 
-            writer.serialize(record)?;
-            self.lines_written += 1;
+    fn end_writing(&mut self, mut writer: csv::Writer<File>) -> Result<()> {
+        writer.flush()?;
+        if let Some(rejected) = &mut self.rejected {
+            rejected.flush()?;
         }
         Ok(())
     }
+
+    fn write_record(&mut self, writer: &mut csv::Writer<File>, record: Record) -> Result<()> {
+        self.total_size += record.size;
+        writer.serialize(record)?;
+        self.lines_written += 1;
+        Ok(())
+    }
+
+    // Lists gathered before timestamps were recorded get them from the file
+    // system, which only works while the files are still there.
+    fn fill_modified(&mut self, record: &mut Record) -> Result<()> {
+        match record
+            .os_path()
+            .and_then(|path| Ok(std::fs::metadata(path)?))
+        {
+            Ok(metadata) => record.modified = modified_time(&metadata),
+            Err(e) => self.reporter.report_error(&record.path, e)?,
+        }
+        Ok(())
+    }
+
+    // Only the first bytes are read, and only for records that passed every
+    // other filter, as reading files is much slower than matching paths.
+    fn has_file_type(&mut self, record: &Record) -> Result<bool> {
+        if self.config.whitelist_file_types.is_empty() {
+            return Ok(true);
+        }
+        let file_type = record.source().and_then(|source| sniff_file_type(&source));
+        self.matches_file_type(record, file_type)
+    }
+
+    fn matches_file_type(
+        &mut self,
+        record: &Record,
+        file_type: Result<Option<FileType>>,
+    ) -> Result<bool> {
+        match file_type {
+            Ok(file_type) => {
+                if let Debug::On = self.config.debug {
+                    println!("path: {:?}, file type: {:?}", record.path, file_type);
+                }
+                Ok(file_type.is_some_and(|file_type| {
+                    self.config
+                        .whitelist_file_types
+                        .contains(&file_type.category)
+                }))
+            }
+            Err(e) => {
+                self.reporter.report_error(&record.path, e)?;
+                Ok(false)
+            }
+        }
+    }
 }
 
-#[derive(Debug)]
+// Patterns given to the path options. By default they are literal substrings,
+// and these prefixes change how they match:
+//   i:     case insensitive, can be combined with the others (i:glob:*.JPG)
+//   re:    regular expression, matched anywhere in the path
+//   glob:  glob over the whole path (**/DCIM/*.jpg), or over the file name
+//          when the pattern has no slash (*.jpg)
+//   name:  exact file name
+//   lit:   literal, to match text that starts with one of these prefixes
+// ':case-insensitive:!' is still accepted as an alias of 'i:'.
+#[derive(Debug, Clone)]
 pub struct FilterPath {
-    path: String,
+    pattern: Pattern,
     case_insensitive: bool,
 }
 
+#[derive(Debug, Clone)]
+enum Pattern {
+    Literal(String),
+    Regex(Regex),
+    Glob { regex: Regex, whole_path: bool },
+    Name(String),
+}
+
 impl FilterPath {
-    pub fn new(path: &str) -> Self {
-        let case_insensitive = path.starts_with(":case-insensitive:!");
-        let path = if case_insensitive {
-            path[":case-insensitive:!".len()..]
-                .to_string()
-                .to_lowercase()
-        } else {
-            path.into()
-        };
+    pub fn with_case(path: &str, case_insensitive: bool) -> Self {
         FilterPath {
-            path,
+            pattern: Pattern::Literal(lowercase_if(path, case_insensitive)),
             case_insensitive,
         }
     }
     pub fn ends_with(&self, other: &str) -> bool {
-        if self.case_insensitive {
-            other.to_lowercase().ends_with(&self.path)
-        } else {
-            other.ends_with(&self.path)
+        match &self.pattern {
+            Pattern::Literal(path) => self.text(other).ends_with(path.as_str()),
+            _ => self.is_match(other),
         }
     }
     pub fn starts_with(&self, other: &str) -> bool {
-        if self.case_insensitive {
-            other.to_lowercase().starts_with(&self.path)
-        } else {
-            other.starts_with(&self.path)
+        match &self.pattern {
+            Pattern::Literal(path) => self.text(other).starts_with(path.as_str()),
+            _ => self.is_match(other),
         }
     }
     pub fn contains(&self, other: &str) -> bool {
+        match &self.pattern {
+            Pattern::Literal(path) => self.text(other).contains(path.as_str()),
+            _ => self.is_match(other),
+        }
+    }
+    fn is_match(&self, other: &str) -> bool {
+        match &self.pattern {
+            Pattern::Literal(path) => self.text(other).contains(path.as_str()),
+            Pattern::Regex(regex) => regex.is_match(other),
+            Pattern::Glob { regex, whole_path } => {
+                regex.is_match(if *whole_path { other } else { file_name(other) })
+            }
+            Pattern::Name(name) => self.text(file_name(other)) == *name,
+        }
+    }
+    fn text<'a>(&self, other: &'a str) -> Cow<'a, str> {
         if self.case_insensitive {
-            other.to_lowercase().contains(&self.path)
+            Cow::Owned(other.to_lowercase())
         } else {
-            other.contains(&self.path)
+            Cow::Borrowed(other)
+        }
+    }
+}
+
+impl std::str::FromStr for FilterPath {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        let mut case_insensitive = false;
+        loop {
+            if let Some(tail) = rest.strip_prefix(":case-insensitive:!") {
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix("i:") {
+                rest = tail;
+            } else {
+                break;
+            }
+            case_insensitive = true;
         }
+        let compile = |pattern: &str| {
+            RegexBuilder::new(pattern)
+                .case_insensitive(case_insensitive)
+                .build()
+                .map_err(|e| format!("Invalid pattern '{}': {}", s, e))
+        };
+        let pattern = if let Some(regex) = rest.strip_prefix("re:") {
+            Pattern::Regex(compile(regex)?)
+        } else if let Some(glob) = rest.strip_prefix("glob:") {
+            Pattern::Glob {
+                regex: compile(&glob_to_regex(glob))?,
+                whole_path: glob.contains('/'),
+            }
+        } else if let Some(name) = rest.strip_prefix("name:") {
+            Pattern::Name(lowercase_if(name, case_insensitive))
+        } else {
+            let literal = rest.strip_prefix("lit:").unwrap_or(rest);
+            Pattern::Literal(lowercase_if(literal, case_insensitive))
+        };
+        Ok(FilterPath {
+            pattern,
+            case_insensitive,
+        })
     }
 }
 
-struct MapValue {
-    path: Option<String>,
+fn lowercase_if(text: &str, lowercase: bool) -> String {
+    if lowercase {
+        text.to_lowercase()
+    } else {
+        text.into()
+    }
 }
 
-impl MapValue {
-    pub fn new(path: String) -> Self {
-        MapValue { path: Some(path) }
+fn file_name(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(slash) => &path[slash + 1..],
+        None => path,
     }
 }
 
-fn is_filtered(config: &FilterPathsConfig, path: &str, size: u64) -> bool {
-    if config.blacklist_path_starts.len() > 0
-        && config
-            .blacklist_path_starts
-            .iter()
-            .any(|p| p.starts_with(path))
-    {
-        return true;
-    }
-    if config.blacklist_path_ends.len() > 0
-        && config.blacklist_path_ends.iter().any(|p| p.ends_with(path))
-    {
-        return true;
-    }
-    if config.blacklist_path_contents.len() > 0
-        && config
-            .blacklist_path_contents
-            .iter()
-            .any(|p| p.contains(path))
-    {
-        return true;
-    }
-    if config.whitelist_path_ends.len() > 0
-        && !config.whitelist_path_ends.iter().any(|p| p.ends_with(path))
-    {
-        return true;
-    }
-    if config.whitelist_path_contents.len() > 0
-        && !config
-            .whitelist_path_contents
-            .iter()
-            .any(|p| p.contains(path))
-    {
-        return true;
+// '**/' matches any number of folders, '*' and '?' don't cross slashes and
+// '[...]' is a character class ('[!...]' negated).
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                let mut class = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == ']' && !class.is_empty() {
+                        closed = true;
+                        break;
+                    }
+                    class.push(c);
+                }
+                if closed {
+                    regex.push('[');
+                    let class = match class.strip_prefix('!') {
+                        Some(negated) => {
+                            regex.push('^');
+                            negated
+                        }
+                        None => &class,
+                    };
+                    for c in class.chars() {
+                        match c {
+                            '-' => regex.push('-'),
+                            c => regex.push_str(&regex::escape(&c.to_string())),
+                        }
+                    }
+                    regex.push(']');
+                } else {
+                    regex.push_str(&regex::escape(&format!("[{}", class)));
+                }
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
     }
+    regex.push('$');
+    regex
+}
+
+// Each option is compiled into one or more rules, using the same kind of
+// expression as the one given by the user, and a record is kept only if all
+// of them match. Blacklisted patterns get a rule each, so the rejected file
+// tells which one excluded a record.
+struct Rule {
+    name: String,
+    filter: FilterExpr,
+}
 
-    if size < config.size_min || size > config.size_max {
-        return true;
+fn group_of(key: GroupKey, record: &Record) -> String {
+    let path = record.text_path();
+    let name = file_name(&path);
+    match key {
+        GroupKey::Size => record.size.to_string(),
+        GroupKey::Hash => record.hash.clone(),
+        GroupKey::Basename => name.into(),
+        GroupKey::BasenameCaseInsensitive => name.to_lowercase(),
+        GroupKey::Stem => {
+            let mut stem = match name.rfind('.') {
+                Some(dot) if dot > 0 => &name[..dot],
+                _ => name,
+            };
+            while let Some(original) = strip_copy_suffix(stem) {
+                stem = original;
+            }
+            stem.into()
+        }
+        GroupKey::Extension => match name.rfind('.') {
+            Some(dot) if dot > 0 => name[dot + 1..].to_lowercase(),
+            _ => String::new(),
+        },
+        // File names can't contain slashes, so they can separate both parts.
+        GroupKey::SizeAndBasename => format!("{}/{}", record.size, name),
     }
-    false
+}
+
+// Windows names copies like "a - Copy.jpg" and "a - Copy (2).jpg".
+fn strip_copy_suffix(stem: &str) -> Option<&str> {
+    if let Some(original) = stem.strip_suffix(" - Copy") {
+        return Some(original);
+    }
+    let (original, number) = stem.strip_suffix(')')?.rsplit_once(" - Copy (")?;
+    if !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) {
+        Some(original)
+    } else {
+        None
+    }
+}
+
+fn build_rules(config: &FilterPathsConfig) -> Result<Vec<Rule>> {
+    let text = |op: TextOp, pattern: &FilterPath| {
+        FilterExpr::Condition(Condition::Text(Field::Path, op, pattern.clone()))
+    };
+    let mut rules = vec![];
+    let mut add = |name: String, filter: FilterExpr| rules.push(Rule { name, filter });
+    let blacklists = [
+        (
+            "blacklist_path_starts",
+            TextOp::StartsWith,
+            &config.blacklist_path_starts,
+        ),
+        (
+            "blacklist_path_ends",
+            TextOp::EndsWith,
+            &config.blacklist_path_ends,
+        ),
+        (
+            "blacklist_path_contents",
+            TextOp::Contains,
+            &config.blacklist_path_contents,
+        ),
+    ];
+    for (name, op, patterns) in blacklists.iter() {
+        for (i, pattern) in patterns.iter().enumerate() {
+            add(format!("{}[{}]", name, i), text(*op, pattern).negate());
+        }
+    }
+    let whitelists = [
+        (
+            "whitelist_path_ends",
+            TextOp::EndsWith,
+            &config.whitelist_path_ends,
+        ),
+        (
+            "whitelist_path_contents",
+            TextOp::Contains,
+            &config.whitelist_path_contents,
+        ),
+    ];
+    for (name, op, patterns) in whitelists.iter() {
+        if !patterns.is_empty() {
+            let any = FilterExpr::any(patterns.iter().map(|pattern| text(*op, pattern)));
+            add(name.to_string(), any);
+        }
+    }
+    if config.size_min > 0 {
+        add(
+            "size_min".into(),
+            FilterExpr::Condition(Condition::Size(CmpOp::Ge, config.size_min)),
+        );
+    }
+    if config.size_max < u64::MAX {
+        add(
+            "size_max".into(),
+            FilterExpr::Condition(Condition::Size(CmpOp::Le, config.size_max)),
+        );
+    }
+    if !config.categories.is_empty() {
+        let extensions =
+            category_extensions(&config.categories, config.categories_file.as_deref())?;
+        add(
+            "category".into(),
+            FilterExpr::Condition(Condition::In(Field::Ext, extensions)),
+        );
+    }
+    if let Some(after) = config.modified_after {
+        add(
+            "modified_after".into(),
+            FilterExpr::Condition(Condition::Modified(CmpOp::Ge, after)),
+        );
+    }
+    if let Some(before) = config.modified_before {
+        add(
+            "modified_before".into(),
+            FilterExpr::Condition(Condition::Modified(CmpOp::Lt, before)),
+        );
+    }
+    if let Some(expression) = &config.expression {
+        add("filter".into(), expression.clone());
+    }
+    Ok(rules)
 }
 
 impl Default for FilterPathsConfig {
@@ -215,8 +703,9 @@ impl Default for FilterPathsConfig {
             target_file: Default::default(),
             debug: Default::default(),
             error_log: Default::default(),
-            unique_sizes: false,
-            unique_hashes: false,
+            size_format: Default::default(),
+            exclude_unique_by: Default::default(),
+            exclude_duplicated_by: Default::default(),
             size_min: std::u64::MIN,
             size_max: std::u64::MAX,
             blacklist_path_starts: Default::default(),
@@ -224,6 +713,15 @@ impl Default for FilterPathsConfig {
             blacklist_path_contents: Default::default(),
             whitelist_path_ends: Default::default(),
             whitelist_path_contents: Default::default(),
+            expression: None,
+            whitelist_file_types: Default::default(),
+            categories: Default::default(),
+            categories_file: None,
+            modified_after: None,
+            modified_before: None,
+            sort_buffer: DEFAULT_SORT_BUFFER,
+            temp_dir: None,
+            rejected_file: None,
         }
     }
 }
@@ -231,6 +729,15 @@ impl Default for FilterPathsConfig {
 #[cfg(test)]
 mod test {
     use super::*;
+
+    fn is_filtered(config: &FilterPathsConfig, path: &str, size: u64) -> bool {
+        let record = Record::for_test(path, size, "NULL");
+        build_rules(config)
+            .unwrap()
+            .iter()
+            .any(|rule| !rule.filter.matches(&record))
+    }
+
     #[test]
     fn test_default_is_not_filtered() {
         let config = FilterPathsConfig::default();
@@ -245,7 +752,7 @@ mod test {
     #[test]
     fn test_whitelist_end_ok_is_not_filtered() {
         let mut config = FilterPathsConfig::default();
-        config.whitelist_path_ends.push(FilterPath::new(".mp3"));
+        config.whitelist_path_ends.push(".mp3".parse().unwrap());
         let actual = is_filtered(
             &config,
             "/mnt/c/Users/Jose/Documents/Old_CDs 1/DVD 2/mIRC/DCC/01 - Oihu.mp3",
@@ -257,7 +764,7 @@ mod test {
     #[test]
     fn test_whitelist_end_not_ok_is_filtered() {
         let mut config = FilterPathsConfig::default();
-        config.whitelist_path_ends.push(FilterPath::new(".png"));
+        config.whitelist_path_ends.push(".png".parse().unwrap());
         let actual = is_filtered(
             &config,
             "/mnt/c/Users/Jose/Documents/Old_CDs 1/DVD 2/mIRC/DCC/01 - Oihu.mp3",
@@ -269,7 +776,7 @@ mod test {
     #[test]
     fn test_whitelist_end_not_case_sensitive_ok_is_filtered() {
         let mut config = FilterPathsConfig::default();
-        config.whitelist_path_ends.push(FilterPath::new(".MP3"));
+        config.whitelist_path_ends.push(".MP3".parse().unwrap());
         let actual = is_filtered(
             &config,
             "/mnt/c/Users/Jose/Documents/Old_CDs 1/DVD 2/mIRC/DCC/01 - Oihu.mp3",
@@ -283,7 +790,7 @@ mod test {
         let mut config = FilterPathsConfig::default();
         config
             .whitelist_path_ends
-            .push(FilterPath::new(":case-insensitive:!.MP3"));
+            .push(":case-insensitive:!.MP3".parse().unwrap());
         let actual = is_filtered(
             &config,
             "/mnt/c/Users/Jose/Documents/Old_CDs 1/DVD 2/mIRC/DCC/01 - Oihu.mp3",
@@ -291,4 +798,331 @@ mod test {
         );
         assert_eq!(actual, false);
     }
+
+    #[test]
+    fn test_glob_over_whole_path() {
+        let pattern: FilterPath = "glob:**/DCIM/*.jpg".parse().unwrap();
+        assert!(pattern.contains("/sd/DCIM/IMG_01.jpg"));
+        assert!(pattern.contains("DCIM/IMG_01.jpg"));
+        assert!(!pattern.contains("/sd/DCIM/sub/IMG_01.jpg"));
+        assert!(!pattern.contains("/sd/DCIM/IMG_01.JPG"));
+    }
+
+    #[test]
+    fn test_glob_without_slash_matches_file_name() {
+        let pattern: FilterPath = "i:glob:IMG_0?.[jp]*".parse().unwrap();
+        assert!(pattern.ends_with("/sd/DCIM/img_01.JPG"));
+        assert!(pattern.ends_with("/sd/DCIM/IMG_02.png"));
+        assert!(!pattern.ends_with("/sd/DCIM/IMG_02.gif"));
+        assert!(!pattern.ends_with("/sd/IMG_01.jpg/a.gif"));
+    }
+
+    #[test]
+    fn test_regex_matches_anywhere() {
+        let pattern: FilterPath = "re:/[0-9]{4}/".parse().unwrap();
+        assert!(pattern.starts_with("/photos/2019/a.jpg"));
+        assert!(!pattern.starts_with("/photos/19/a.jpg"));
+    }
+
+    #[test]
+    fn test_name_matches_exact_file_name() {
+        let pattern: FilterPath = "i:name:thumbs.db".parse().unwrap();
+        assert!(pattern.contains("/photos/Thumbs.db"));
+        assert!(!pattern.contains("/photos/Thumbs.db.bak"));
+    }
+
+    #[test]
+    fn test_literal_prefix_escapes_other_prefixes() {
+        let pattern: FilterPath = "lit:re:".parse().unwrap();
+        assert!(pattern.contains("/mail/re: hello.eml"));
+        assert!(!pattern.contains("/mail/hello.eml"));
+    }
+
+    #[test]
+    fn test_invalid_regex_is_an_error() {
+        assert!("re:(".parse::<FilterPath>().is_err());
+    }
+
+    #[test]
+    fn test_rejected_records_name_their_rule() {
+        let dir = tempfile::tempdir().unwrap();
+        let source_file = dir.path().join("in.csv");
+        std::fs::write(
+            &source_file,
+            "path,size,hash\n/a.mp3,5,NULL\n/b.tmp,5,NULL\n/c.bak,5,NULL\n/d.mp3,9,NULL\n/e.mp3,500,NULL\n/f.mp3,5,NULL\n",
+        )
+        .unwrap();
+        let config = FilterPathsConfig {
+            source_file,
+            target_file: dir.path().join("out.csv"),
+            rejected_file: Some(dir.path().join("rejected.csv")),
+            blacklist_path_ends: vec![".tmp".parse().unwrap(), ".bak".parse().unwrap()],
+            size_max: 100,
+            exclude_unique_by: vec![GroupKey::Size],
+            sort_buffer: 1,
+            ..FilterPathsConfig::default()
+        };
+        let mut ctx = Context::new(config).unwrap();
+        ctx.process().unwrap();
+        let rejected = std::fs::read_to_string(dir.path().join("rejected.csv")).unwrap();
+        assert_eq!(
+            rejected,
+            "path,size,hash,modified,dir_modified,in_archive,whole_hash,rule\n\
+             /b.tmp,5,NULL,,,false,false,blacklist_path_ends[0]\n\
+             /c.bak,5,NULL,,,false,false,blacklist_path_ends[1]\n\
+             /e.mp3,500,NULL,,,false,false,size_max\n\
+             /d.mp3,9,NULL,,,false,false,unique_size\n"
+        );
+        let written = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(
+            written.lines().skip(1).collect::<Vec<_>>(),
+            vec!["/a.mp3,5,NULL,,,false,false", "/f.mp3,5,NULL,,,false,false"]
+        );
+        assert_eq!(ctx.hits[ctx.rules.len() + UNIQUE_RULE].1, 1);
+    }
+
+    #[test]
+    fn test_file_types_of_archive_members() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("a.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+        for (name, content) in &[
+            ("a.jpg", &b"\xFF\xD8\xFF\xE0"[..]),
+            ("b.txt", b"text"),
+            ("c.jpg", b"\xFF\xD8\xFF\xE1"),
+        ] {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            std::io::Write::write_all(&mut zip, content).unwrap();
+        }
+        zip.finish().unwrap();
+        let loose = dir.path().join("d.jpg");
+        std::fs::write(&loose, b"\xFF\xD8\xFF\xE0").unwrap();
+        let source_file = dir.path().join("in.csv");
+        std::fs::write(
+            &source_file,
+            format!(
+                "path,size,hash,modified,dir_modified,in_archive\n\
+                 {0}!/c.jpg,4,NULL,,,true\n{0}!/b.txt,4,NULL,,,true\n{1},4,NULL,,,false\n\
+                 {0}!/a.jpg,4,NULL,,,true\n{0}!/missing,4,NULL,,,true\n",
+                archive.display(),
+                loose.display()
+            ),
+        )
+        .unwrap();
+        let config = FilterPathsConfig {
+            source_file,
+            target_file: dir.path().join("out.csv"),
+            whitelist_file_types: vec![FileCategory::Image],
+            ..FilterPathsConfig::default()
+        };
+        let mut ctx = Context::new(config).unwrap();
+        ctx.process().unwrap();
+        assert_eq!(ctx.reporter.error_count(), 1);
+        assert_eq!(ctx.hits[ctx.rules.len() + FILE_TYPE_RULE].1, 2);
+        let written: Vec<String> = csv::Reader::from_path(dir.path().join("out.csv"))
+            .unwrap()
+            .deserialize()
+            .map(|record: csv::Result<Record>| record.unwrap().path)
+            .collect();
+        let member = |name: &str| format!("{}!/{}", archive.display(), name);
+        assert_eq!(
+            written,
+            vec![
+                member("c.jpg"),
+                loose.display().to_string(),
+                member("a.jpg")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unique_records_are_counted_without_rejected_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let source_file = dir.path().join("in.csv");
+        std::fs::write(
+            &source_file,
+            "path,size,hash\n/a.mp3,5,NULL\n/b.mp3,6,NULL\n/c.mp3,5,NULL\n/d.mp3,7,NULL\n",
+        )
+        .unwrap();
+        let config = FilterPathsConfig {
+            source_file,
+            target_file: dir.path().join("out.csv"),
+            exclude_unique_by: vec![GroupKey::Size],
+            sort_buffer: 1,
+            ..FilterPathsConfig::default()
+        };
+        let mut ctx = Context::new(config).unwrap();
+        ctx.process().unwrap();
+        assert_eq!(ctx.lines_written, 2);
+        assert_eq!(
+            ctx.hits[ctx.rules.len() + UNIQUE_RULE],
+            ("unique_size".into(), 2)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_paths_are_matched_decoded() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = std::ffi::OsStr::from_bytes(b"/trash/IMG\xff - Copy.jpg");
+        let path = crate::internals::encode_path(std::path::Path::new(path)).unwrap();
+        assert!(path.starts_with(":os-bytes:!"));
+        let config = FilterPathsConfig {
+            blacklist_path_starts: vec!["/trash/".parse().unwrap()],
+            ..FilterPathsConfig::default()
+        };
+        assert!(is_filtered(&config, &path, 1));
+        let config = FilterPathsConfig {
+            whitelist_path_ends: vec!["i:glob:*.JPG".parse().unwrap()],
+            ..FilterPathsConfig::default()
+        };
+        assert!(!is_filtered(&config, &path, 1));
+        assert_eq!(
+            group_of(GroupKey::Stem, &Record::for_test(&path, 1, "NULL")),
+            "IMG\u{fffd}"
+        );
+    }
+
+    #[test]
+    fn test_stem_ignores_copy_suffixes() {
+        for path in &[
+            "/a/IMG 1.jpg",
+            "/b/IMG 1 - Copy.jpg",
+            "/c/IMG 1 - Copy (2) - Copy.png",
+        ] {
+            assert_eq!(
+                group_of(GroupKey::Stem, &Record::for_test(path, 1, "NULL")),
+                "IMG 1"
+            );
+        }
+        assert_eq!(
+            group_of(
+                GroupKey::Stem,
+                &Record::for_test("/IMG 1 - Copy (x).jpg", 1, "NULL")
+            ),
+            "IMG 1 - Copy (x)"
+        );
+        assert_eq!(
+            group_of(
+                GroupKey::Extension,
+                &Record::for_test("/a/b.JPG", 1, "NULL")
+            ),
+            "jpg"
+        );
+        assert_eq!(
+            group_of(
+                GroupKey::Extension,
+                &Record::for_test("/a/.bashrc", 1, "NULL")
+            ),
+            ""
+        );
+        assert_eq!(
+            group_of(
+                GroupKey::SizeAndBasename,
+                &Record::for_test("/a/b.jpg", 7, "NULL")
+            ),
+            "7/b.jpg"
+        );
+    }
+
+    #[test]
+    fn test_group_keys_count_rejections_without_rejected_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let source_file = dir.path().join("in.csv");
+        std::fs::write(
+            &source_file,
+            "path,size,hash\n/x/a.jpg,5,h1\n/y/a.jpg,6,h1\n/y/A.JPG,5,h2\n\
+             /z/b.png,7,h3\n/z/c - Copy.png,8,h3\n/z/c.txt,9,h5\n",
+        )
+        .unwrap();
+        let cases = [
+            (GroupKey::Size, 4),
+            (GroupKey::Hash, 2),
+            (GroupKey::Basename, 4),
+            (GroupKey::BasenameCaseInsensitive, 3),
+            (GroupKey::Stem, 2),
+            (GroupKey::Extension, 1),
+            (GroupKey::SizeAndBasename, 6),
+        ];
+        for (key, unique) in cases {
+            let config = FilterPathsConfig {
+                source_file: source_file.clone(),
+                target_file: dir.path().join("out.csv"),
+                exclude_unique_by: vec![key],
+                sort_buffer: 2,
+                ..FilterPathsConfig::default()
+            };
+            let mut ctx = Context::new(config).unwrap();
+            ctx.process().unwrap();
+            assert_eq!(ctx.lines_written, 6 - unique, "{}", key.name());
+            assert_eq!(
+                ctx.hits[ctx.rules.len() + UNIQUE_RULE].1,
+                unique,
+                "{}",
+                key.name()
+            );
+
+            let config = FilterPathsConfig {
+                source_file: source_file.clone(),
+                target_file: dir.path().join("out.csv"),
+                exclude_duplicated_by: vec![key],
+                sort_buffer: 2,
+                ..FilterPathsConfig::default()
+            };
+            let mut ctx = Context::new(config).unwrap();
+            ctx.process().unwrap();
+            assert_eq!(ctx.lines_written, unique, "{}", key.name());
+            assert_eq!(
+                ctx.hits[ctx.rules.len() + DUPLICATED_RULE].1,
+                6 - unique,
+                "{}",
+                key.name()
+            );
+        }
+    }
+
+    #[test]
+    fn test_exclude_unique_and_duplicated_by_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let source_file = dir.path().join("in.csv");
+        std::fs::write(
+            &source_file,
+            "path,size,hash
+/x/A.jpg,5,NULL
+/y/a.jpg,6,NULL
+/y/b.jpg,5,NULL
+/z/b.jpg,5,NULL
+/z/c.png,7,NULL
+",
+        )
+        .unwrap();
+        let config = FilterPathsConfig {
+            source_file,
+            target_file: dir.path().join("out.csv"),
+            rejected_file: Some(dir.path().join("rejected.csv")),
+            exclude_unique_by: vec![GroupKey::BasenameCaseInsensitive],
+            exclude_duplicated_by: vec![GroupKey::SizeAndBasename],
+            sort_buffer: 2,
+            ..FilterPathsConfig::default()
+        };
+        let mut ctx = Context::new(config).unwrap();
+        ctx.process().unwrap();
+        let rejected = std::fs::read_to_string(dir.path().join("rejected.csv")).unwrap();
+        assert_eq!(
+            rejected,
+            "path,size,hash,modified,dir_modified,in_archive,whole_hash,rule\n\
+             /y/b.jpg,5,NULL,,,false,false,duplicated_size_basename\n\
+             /z/b.jpg,5,NULL,,,false,false,duplicated_size_basename\n\
+             /z/c.png,7,NULL,,,false,false,unique_basename_ci\n"
+        );
+        let written = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(
+            written.lines().skip(1).collect::<Vec<_>>(),
+            vec![
+                "/x/A.jpg,5,NULL,,,false,false",
+                "/y/a.jpg,6,NULL,,,false,false"
+            ]
+        );
+    }
 }
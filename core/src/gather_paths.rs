@@ -1,10 +1,18 @@
-use crate::common::{Debug, TraverseMode};
-use crate::internals::{Record, Reporter};
+use crate::archives::{list_members, virtual_path, ArchiveKind};
+use crate::common::{Debug, SizeFormat, SortOrder, TraverseMode};
+use crate::internals::{
+    compare_bytes, compare_names, device_id, encode_path, modified_time, Record, Reporter,
+};
 use anyhow::{anyhow, Result};
 use num_format::{Locale, ToFormattedString};
-use size_format::SizeFormatterSI;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
 use std::fs::{read_dir, DirEntry, File, Metadata};
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
 use std::time::Instant;
 
 #[derive(Debug)]
@@ -13,139 +21,644 @@ pub struct GatherPathsConfig {
     pub target_file: PathBuf,
     pub traverse_mode: TraverseMode,
     pub unsorted: bool,
+    pub sort_order: SortOrder,
+    pub threads: usize,
+    pub min_depth: usize,
+    pub max_depth: Option<usize>,
+    pub one_file_system: bool,
+    pub previous_snapshot: Option<PathBuf>,
+    pub only_deltas: bool,
+    pub scan_archives: bool,
     pub debug: Debug,
     pub error_log: Option<PathBuf>,
+    pub size_format: SizeFormat,
 }
 
 pub fn gather_paths(config: GatherPathsConfig) -> Result<()> {
     println!("GATHER PATHS | config: {:?}", config);
     let now = Instant::now();
-    let mut ctx = Context::new(config)?;
-    let mut source_paths = ctx.config.source_paths.clone();
-    if !ctx.config.unsorted {
-        source_paths.sort_by(std::cmp::Ord::cmp);
+    let mut ctx = Context::new(&config)?;
+    let snapshot = match &config.previous_snapshot {
+        Some(path) => load_snapshot(path)?,
+        None => Snapshot::default(),
+    };
+    let mut source_paths = config.source_paths.clone();
+    if !config.unsorted {
+        let order = config.sort_order;
+        source_paths.sort_by(|a, b| compare_names(order, a.as_os_str(), b.as_os_str()));
     }
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(config.threads)
+        .build()?;
     for path in source_paths.into_iter() {
-        process_path(&mut ctx, &path)?;
+        let walk = Walk {
+            config: &config,
+            snapshot: &snapshot,
+            device: if config.one_file_system {
+                Some(device_id(&std::fs::metadata(&path)?)?)
+            } else {
+                None
+            },
+        };
+        ctx.write_path(&pool, &walk, &path)?;
     }
     ctx.end_writing()?;
     println!("Duration: {:#?}", (Instant::now() - now));
     println!(
         "Written {} lines {:?}",
         ctx.lines_written.to_formatted_string(&Locale::en),
-        ctx.config.target_file
+        config.target_file
     );
+    if config.previous_snapshot.is_some() {
+        println!(
+            "Reused from snapshot: {}",
+            ctx.lines_reused.to_formatted_string(&Locale::en)
+        );
+    }
     println!(
-        "Size of all files: {}B",
-        SizeFormatterSI::new(ctx.total_size)
+        "Size of all files: {}",
+        config.size_format.format(ctx.total_size)
     );
     println!(
         "Errors: {} ({:?})",
         ctx.reporter.error_count().to_formatted_string(&Locale::en),
-        ctx.config.error_log
+        config.error_log
     );
     Ok(())
 }
 
 struct Context {
-    config: GatherPathsConfig,
+    debug: Debug,
+    only_deltas: bool,
     reporter: Reporter,
     lines_written: u64,
+    lines_reused: u64,
     csv_out: csv::Writer<File>,
     total_size: u64,
 }
 
 impl Context {
-    pub fn new(config: GatherPathsConfig) -> Result<Context> {
+    pub fn new(config: &GatherPathsConfig) -> Result<Context> {
         Ok(Context {
+            debug: config.debug,
+            only_deltas: config.only_deltas,
             csv_out: csv::Writer::from_writer(File::create(&config.target_file)?),
             reporter: Reporter::new(config.error_log.clone(), config.debug),
-            config,
             lines_written: 0,
+            lines_reused: 0,
             total_size: 0,
         })
     }
     fn write_record(&mut self, record: Record) -> Result<()> {
+        if let Debug::On = self.debug {
+            println!("path: {:?}, size: {}", record.path, record.size);
+        }
+        self.total_size += record.size;
         self.csv_out.serialize(record)?;
         self.lines_written += 1;
         Ok(())
     }
+    // Folders are written depth-first as they are walked. The pool reads
+    // the folders that come next in the output while earlier ones are
+    // written, and never blocks on the writer.
+    fn write_path<'a>(&mut self, pool: &ThreadPool, walk: &'a Walk<'a>, path: &Path) -> Result<()> {
+        let read_ahead = READ_AHEAD_PER_THREAD * pool.current_num_threads();
+        let root = pool.install(|| list_dir(walk, path, 1))?;
+        pool.in_place_scope(|scope| {
+            let mut stack = vec![root];
+            let mut reading = 0;
+            loop {
+                for listing in stack.iter_mut().rev() {
+                    while reading < read_ahead && listing.started < listing.entries.len() {
+                        let slot = &mut listing.entries[listing.started];
+                        listing.started += 1;
+                        if let Slot::Waiting(_) = slot {
+                            let (sender, receiver) = sync_channel(1);
+                            if let Slot::Waiting(entry) =
+                                std::mem::replace(slot, Slot::Started(receiver))
+                            {
+                                let dir = listing.dir.clone();
+                                let depth = listing.depth;
+                                scope.spawn(move |_| {
+                                    let _ = sender.send(process_entry(walk, &dir, entry, depth));
+                                });
+                            }
+                            reading += 1;
+                        }
+                    }
+                }
+                let listing = match stack.last_mut() {
+                    Some(listing) => listing,
+                    None => return Ok(()),
+                };
+                let slot = match listing.entries.pop_front() {
+                    Some(slot) => slot,
+                    None => {
+                        stack.pop();
+                        continue;
+                    }
+                };
+                listing.started = listing.started.saturating_sub(1);
+                let node = match slot {
+                    Slot::Done(node) => node,
+                    Slot::Started(receiver) => {
+                        reading -= 1;
+                        receiver
+                            .recv()
+                            .map_err(|_| anyhow!("A thread walking {:?} stopped", path))?
+                    }
+                    // Folders read ahead in the parent folders may take all the room.
+                    Slot::Waiting(entry) => {
+                        let (dir, depth) = (&listing.dir, listing.depth);
+                        pool.install(|| process_entry(walk, dir, entry, depth))
+                    }
+                };
+                if let Some(node) = node {
+                    self.write_node(node, &mut stack)?;
+                }
+            }
+        })
+    }
+    fn write_node<'a>(&mut self, node: Node<'a>, stack: &mut Vec<Listing<'a>>) -> Result<()> {
+        match node {
+            Node::File { record, reused } => {
+                if reused {
+                    self.lines_reused += 1;
+                    if self.only_deltas {
+                        return Ok(());
+                    }
+                }
+                self.write_record(record)?
+            }
+            Node::Archive(nodes) => {
+                for node in nodes.into_iter() {
+                    self.write_node(node, stack)?;
+                }
+            }
+            Node::Dir(listing) => stack.push(listing),
+            Node::Error(entry, e) => self.reporter.report_error(&entry, e)?,
+        }
+        Ok(())
+    }
     fn end_writing(&mut self) -> Result<()> {
+        self.csv_out.flush()?;
         Ok(())
     }
 }
 
-fn process_path(ctx: &mut Context, path: &Path) -> Result<()> {
-    for entry in read_dir(&path)? {
-        let entry = entry?;
-        let ty = entry.file_type()?;
-        if ty.is_dir() {
-            process_dir_1(ctx, &entry)?;
-        }
-        if ty.is_file() {
-            process_file_1(ctx, &entry)?;
-        }
-        if ty.is_symlink() {
+// Records from a previous run grouped by folder and by archive, so files in
+// folders and archives that didn't change since then don't need to be read again.
+#[derive(Default)]
+struct Snapshot {
+    dirs: HashMap<PathBuf, SnapshotDir>,
+    archives: HashMap<PathBuf, Vec<Record>>,
+}
+
+struct SnapshotDir {
+    modified: Option<i64>,
+    files: HashMap<OsString, Record>,
+}
+
+fn load_snapshot(path: &Path) -> Result<Snapshot> {
+    let mut snapshot = Snapshot::default();
+    let mut reader = csv::Reader::from_reader(File::open(path)?);
+    for record in reader.deserialize() {
+        let record: Record = record?;
+        let source = record.source()?;
+        if let Some(virtual_path) = source.member {
+            snapshot
+                .archives
+                .entry(virtual_path.archive)
+                .or_default()
+                .push(record);
             continue;
         }
+        let path = source.path;
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent.to_owned(), name.to_owned()),
+            _ => continue,
+        };
+        let dir = snapshot.dirs.entry(parent).or_insert_with(|| SnapshotDir {
+            modified: record.dir_modified,
+            files: HashMap::new(),
+        });
+        dir.files.insert(name, record);
     }
-    Ok(())
+    Ok(snapshot)
+}
+
+// Folders being read ahead for each thread, at most, while the output is
+// written in order.
+const READ_AHEAD_PER_THREAD: usize = 4;
+
+struct Walk<'a> {
+    config: &'a GatherPathsConfig,
+    snapshot: &'a Snapshot,
+    device: Option<u64>,
+}
+
+struct WalkDir<'a> {
+    modified: Option<i64>,
+    previous: Option<&'a SnapshotDir>,
+    unchanged: bool,
 }
 
-fn process_file_1(ctx: &mut Context, entry: &DirEntry) -> Result<()> {
-    match process_file_2(ctx, entry) {
-        Ok(()) => {}
-        Err(e) => ctx.reporter.report_error(entry, e)?,
+// A folder being written, with its entries in output order. Files are read
+// with the folder, subfolders later on. The first `started` entries were
+// looked at for reading ahead.
+struct Listing<'a> {
+    dir: Arc<WalkDir<'a>>,
+    depth: usize,
+    entries: VecDeque<Slot<'a>>,
+    started: usize,
+}
+
+enum Slot<'a> {
+    Waiting(DirEntry),
+    Started(Receiver<Option<Node<'a>>>),
+    Done(Option<Node<'a>>),
+}
+
+enum Node<'a> {
+    File { record: Record, reused: bool },
+    Archive(Vec<Node<'a>>),
+    Dir(Listing<'a>),
+    Error(DirEntry, anyhow::Error),
+}
+
+// Entries directly inside a source path are at depth 1.
+fn list_dir<'a>(walk: &Walk<'a>, path: &Path, depth: usize) -> Result<Listing<'a>> {
+    let modified = modified_time(&std::fs::metadata(path)?);
+    let previous = walk.snapshot.dirs.get(path);
+    let dir = WalkDir {
+        modified,
+        previous,
+        unchanged: modified.is_some() && previous.map(|dir| dir.modified) == Some(modified),
     };
-    Ok(())
+    let mut entries = read_dir(path)?.collect::<std::io::Result<Vec<DirEntry>>>()?;
+    if !walk.config.unsorted {
+        entries
+            .sort_by(|a, b| compare_names(walk.config.sort_order, &a.file_name(), &b.file_name()));
+    }
+    let entries: Vec<Slot> = entries
+        .into_par_iter()
+        .map(|entry| match entry.file_type() {
+            Ok(ty) if ty.is_dir() => Slot::Waiting(entry),
+            _ => Slot::Done(process_entry(walk, &dir, entry, depth)),
+        })
+        .collect();
+    Ok(Listing {
+        dir: Arc::new(dir),
+        depth,
+        entries: entries.into(),
+        started: 0,
+    })
+}
+
+fn process_entry<'a>(
+    walk: &Walk<'a>,
+    dir: &WalkDir,
+    entry: DirEntry,
+    depth: usize,
+) -> Option<Node<'a>> {
+    let ty = match entry.file_type() {
+        Ok(ty) => ty,
+        Err(e) => return Some(Node::Error(entry, e.into())),
+    };
+    if ty.is_dir() {
+        return process_dir_1(walk, entry, depth);
+    }
+    if ty.is_file() && depth >= walk.config.min_depth {
+        return Some(process_file_1(walk, dir, entry));
+    }
+    None
 }
 
-fn process_file_2(ctx: &mut Context, entry: &DirEntry) -> Result<()> {
-    process_file_3(ctx, &entry.path(), entry.metadata()?)
+fn process_file_1<'a>(walk: &Walk, dir: &WalkDir, entry: DirEntry) -> Node<'a> {
+    match process_file_2(dir, &entry) {
+        Ok((record, reused)) => match ArchiveKind::from_path(&entry.path()) {
+            Some(kind) if walk.config.scan_archives => {
+                process_archive_1(walk, entry, kind, record, reused)
+            }
+            _ => Node::File { record, reused },
+        },
+        Err(e) => Node::Error(entry, e),
+    }
 }
 
-fn process_file_3(ctx: &mut Context, path: &Path, metadata: Metadata) -> Result<()> {
-    if let Debug::On = ctx.config.debug {
-        print!("path: {:?}", path);
+fn process_file_2(dir: &WalkDir, entry: &DirEntry) -> Result<(Record, bool)> {
+    let previous = dir
+        .previous
+        .and_then(|previous| previous.files.get(&entry.file_name()));
+    if let (true, Some(previous)) = (dir.unchanged, previous) {
+        return Ok((previous.clone(), true));
     }
-    let record = Record {
-        path: path
-            .to_str()
-            .ok_or_else(|| anyhow!("Couldn't turn path into a str."))?
-            .into(),
+    let record = process_file_3(&entry.path(), entry.metadata()?, dir.modified)?;
+    if let Some(previous) = previous {
+        if previous.size == record.size && previous.modified == record.modified {
+            let mut previous = previous.clone();
+            previous.dir_modified = dir.modified;
+            return Ok((previous, true));
+        }
+    }
+    Ok((record, false))
+}
+
+fn process_file_3(path: &Path, metadata: Metadata, dir_modified: Option<i64>) -> Result<Record> {
+    Ok(Record {
+        path: encode_path(path)?,
         size: metadata.len(),
         hash: "NULL".into(),
-    };
-    if let Debug::On = ctx.config.debug {
-        print!(", size: {}", record.size);
+        modified: modified_time(&metadata),
+        dir_modified,
+        in_archive: false,
+        whole_hash: false,
+    })
+}
+
+// Archives are emitted as a regular file followed by the files inside them.
+fn process_archive_1<'a>(
+    walk: &Walk,
+    entry: DirEntry,
+    kind: ArchiveKind,
+    record: Record,
+    reused: bool,
+) -> Node<'a> {
+    let path = entry.path();
+    let mut nodes = vec![Node::File { record, reused }];
+    match walk.snapshot.archives.get(&path) {
+        Some(previous) if reused => {
+            nodes.extend(previous.iter().map(|record| Node::File {
+                record: record.clone(),
+                reused: true,
+            }));
+        }
+        _ => match process_archive_2(walk, &path, kind) {
+            Ok(records) => nodes.extend(records.into_iter().map(|record| Node::File {
+                record,
+                reused: false,
+            })),
+            Err(e) => nodes.push(Node::Error(entry, e)),
+        },
     }
-    ctx.total_size += record.size;
-    ctx.write_record(record)?;
-    if let Debug::On = ctx.config.debug {
-        println!();
+    Node::Archive(nodes)
+}
+
+fn process_archive_2(walk: &Walk, path: &Path, kind: ArchiveKind) -> Result<Vec<Record>> {
+    let mut members = list_members(path, kind)?;
+    if !walk.config.unsorted {
+        let order = walk.config.sort_order;
+        members.sort_by(|a, b| compare_bytes(order, &a.name, &b.name));
     }
-    Ok(())
+    members
+        .into_iter()
+        .map(|member| {
+            Ok(Record {
+                path: encode_path(&virtual_path(path, &member.name))?,
+                size: member.size,
+                hash: "NULL".into(),
+                modified: member.modified,
+                dir_modified: None,
+                in_archive: true,
+                whole_hash: false,
+            })
+        })
+        .collect()
 }
 
-fn process_dir_1(ctx: &mut Context, entry: &DirEntry) -> Result<()> {
-    match process_dir_2(ctx, entry) {
-        Ok(()) => {}
-        Err(e) => ctx.reporter.report_error(entry, e)?,
-    };
-    Ok(())
+fn process_dir_1<'a>(walk: &Walk<'a>, entry: DirEntry, depth: usize) -> Option<Node<'a>> {
+    match process_dir_2(walk, &entry, depth) {
+        Ok(Some(listing)) => Some(Node::Dir(listing)),
+        Ok(None) => None,
+        Err(e) => Some(Node::Error(entry, e)),
+    }
 }
 
-fn process_dir_2(ctx: &mut Context, entry: &DirEntry) -> Result<()> {
-    if let TraverseMode::Recursive = ctx.config.traverse_mode {
-        process_path(ctx, &entry.path())?;
+fn process_dir_2<'a>(
+    walk: &Walk<'a>,
+    entry: &DirEntry,
+    depth: usize,
+) -> Result<Option<Listing<'a>>> {
+    if let TraverseMode::NonRecursive = walk.config.traverse_mode {
+        return Ok(None);
     }
-    Ok(())
+    if let Some(max_depth) = walk.config.max_depth {
+        if depth >= max_depth {
+            return Ok(None);
+        }
+    }
+    if let Some(device) = walk.device {
+        if device_id(&entry.metadata()?)? != device {
+            return Ok(None);
+        }
+    }
+    Ok(Some(list_dir(walk, &entry.path(), depth + 1)?))
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    fn config(source: &Path, target_file: PathBuf) -> GatherPathsConfig {
+        GatherPathsConfig {
+            source_paths: vec![source.to_owned()],
+            target_file,
+            traverse_mode: TraverseMode::Recursive,
+            unsorted: false,
+            sort_order: SortOrder::ByteWise,
+            threads: 1,
+            min_depth: 0,
+            max_depth: None,
+            one_file_system: false,
+            previous_snapshot: None,
+            only_deltas: false,
+            scan_archives: false,
+            debug: Debug::Off,
+            error_log: None,
+            size_format: SizeFormat::Si,
+        }
+    }
+
+    #[test]
+    fn test_parallel_output_matches_sequential_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("in");
+        for a in 0..6 {
+            for b in 0..6 {
+                let folder = source.join(format!("{}/{}", a, b));
+                std::fs::create_dir_all(&folder).unwrap();
+                for c in 0..(a * b) {
+                    std::fs::write(folder.join(format!("{}.txt", c)), "x".repeat(c)).unwrap();
+                }
+            }
+            std::fs::write(source.join(format!("{}.txt", a)), "").unwrap();
+        }
+        let sequential = dir.path().join("sequential.csv");
+        gather_paths(config(&source, sequential.clone())).unwrap();
+        let parallel = dir.path().join("parallel.csv");
+        gather_paths(GatherPathsConfig {
+            threads: 8,
+            ..config(&source, parallel.clone())
+        })
+        .unwrap();
+        let sequential = std::fs::read(sequential).unwrap();
+        assert_eq!(sequential, std::fs::read(parallel).unwrap());
+        let mut reader = csv::Reader::from_reader(&sequential[..]);
+        let paths: Vec<String> = reader
+            .deserialize()
+            .map(|record: csv::Result<Record>| record.unwrap().path)
+            .collect();
+        assert_eq!(paths.len(), 6 + (0..6).map(|a| a * 15).sum::<usize>());
+        let prefix = format!("{}/", source.display());
+        assert_eq!(paths[0], format!("{}0.txt", prefix));
+        assert_eq!(paths[1], format!("{}1/1/0.txt", prefix));
+        assert_eq!(paths[2], format!("{}1/2/0.txt", prefix));
+        assert_eq!(paths[paths.len() - 2], format!("{}5/5/9.txt", prefix));
+        assert_eq!(paths[paths.len() - 1], format!("{}5.txt", prefix));
+    }
+
+    fn gathered_paths(config: GatherPathsConfig) -> Vec<String> {
+        let target_file = config.target_file.clone();
+        let prefix = format!("{}/", config.source_paths[0].display());
+        gather_paths(config).unwrap();
+        csv::Reader::from_path(target_file)
+            .unwrap()
+            .deserialize()
+            .map(|record: csv::Result<Record>| {
+                let path = record.unwrap().path;
+                path.strip_prefix(&prefix).unwrap().to_string()
+            })
+            .collect()
+    }
+
+    fn nested_folders(source: &Path) {
+        std::fs::create_dir_all(source.join("a/b")).unwrap();
+        std::fs::write(source.join("1.txt"), "").unwrap();
+        std::fs::write(source.join("a/2.txt"), "").unwrap();
+        std::fs::write(source.join("a/b/3.txt"), "").unwrap();
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("in");
+        nested_folders(&source);
+        let target_file = dir.path().join("paths.csv");
+        let gathered = |max_depth| {
+            gathered_paths(GatherPathsConfig {
+                max_depth,
+                ..config(&source, target_file.clone())
+            })
+        };
+        assert_eq!(gathered(None), vec!["1.txt", "a/2.txt", "a/b/3.txt"]);
+        assert_eq!(gathered(Some(2)), vec!["1.txt", "a/2.txt"]);
+        assert_eq!(gathered(Some(1)), vec!["1.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_one_file_system() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("in");
+        nested_folders(&source);
+        let target_file = dir.path().join("paths.csv");
+        assert_eq!(
+            gathered_paths(GatherPathsConfig {
+                one_file_system: true,
+                ..config(&source, target_file.clone())
+            }),
+            vec!["1.txt", "a/2.txt", "a/b/3.txt"]
+        );
+        // Folders are only entered when they are on the device of the input path.
+        let config = config(&source, target_file);
+        let snapshot = Snapshot::default();
+        let device = device_id(&std::fs::metadata(&source).unwrap()).unwrap();
+        let entry = read_dir(&source)
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|entry| entry.file_name() == "a")
+            .unwrap();
+        let walk = |device| Walk {
+            config: &config,
+            snapshot: &snapshot,
+            device,
+        };
+        assert!(process_dir_2(&walk(Some(device)), &entry, 1)
+            .unwrap()
+            .is_some());
+        assert!(
+            process_dir_2(&walk(Some(device.wrapping_add(1))), &entry, 1)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    fn set_dir_modified(path: &Path, timestamp: u64) {
+        let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(timestamp);
+        File::open(path).unwrap().set_modified(time).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_reuse_and_deltas() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("in");
+        std::fs::create_dir_all(source.join("a")).unwrap();
+        std::fs::create_dir_all(source.join("b")).unwrap();
+        std::fs::write(source.join("a/1.txt"), "1").unwrap();
+        std::fs::write(source.join("a/2.txt"), "2").unwrap();
+        std::fs::write(source.join("b/3.txt"), "3").unwrap();
+        set_dir_modified(&source.join("a"), 1_500_000_000);
+        set_dir_modified(&source.join("b"), 1_500_000_000);
+        let snapshot = dir.path().join("snapshot.csv");
+        gather_paths(config(&source, snapshot.clone())).unwrap();
+
+        // a changes, b keeps its modification time even though a file in it
+        // was rewritten, so the old record of that file is reused.
+        std::fs::write(source.join("a/2.txt"), "22").unwrap();
+        std::fs::write(source.join("a/4.txt"), "4").unwrap();
+        std::fs::write(source.join("b/3.txt"), "333").unwrap();
+        set_dir_modified(&source.join("a"), 1_600_000_000);
+        set_dir_modified(&source.join("b"), 1_500_000_000);
+        let gathered = |only_deltas| {
+            let target_file = dir.path().join("paths.csv");
+            gather_paths(GatherPathsConfig {
+                previous_snapshot: Some(snapshot.clone()),
+                only_deltas,
+                ..config(&source, target_file.clone())
+            })
+            .unwrap();
+            let prefix = format!("{}/", source.display());
+            csv::Reader::from_path(target_file)
+                .unwrap()
+                .deserialize()
+                .map(|record: csv::Result<Record>| {
+                    let record = record.unwrap();
+                    let path = record.path.strip_prefix(&prefix).unwrap().to_string();
+                    (path, record.size, record.dir_modified)
+                })
+                .collect::<Vec<_>>()
+        };
+        let (old, new) = (Some(1_500_000_000), Some(1_600_000_000));
+        assert_eq!(
+            gathered(false),
+            vec![
+                ("a/1.txt".to_string(), 1, new),
+                ("a/2.txt".to_string(), 2, new),
+                ("a/4.txt".to_string(), 1, new),
+                ("b/3.txt".to_string(), 1, old),
+            ]
+        );
+        assert_eq!(
+            gathered(true),
+            vec![
+                ("a/2.txt".to_string(), 2, new),
+                ("a/4.txt".to_string(), 1, new)
+            ]
+        );
+    }
 }
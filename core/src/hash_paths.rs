@@ -1,9 +1,10 @@
+use crate::archives::MemberBatch;
 use crate::common::{Debug, HashAlgorithm};
-use crate::internals::{compute_hash, Record, Reporter};
+use crate::internals::{compute_hash, hash_reader, Record, Reporter};
 use anyhow::Result;
 use num_format::{Locale, ToFormattedString};
 use std::fs::File;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 use std::time::Instant;
 
 #[derive(Debug)]
@@ -40,6 +41,7 @@ struct Context {
     config: HashPathsConfig,
     reporter: Reporter,
     lines_written: u64,
+    members: MemberBatch<Record>,
 }
 
 impl Context {
@@ -48,6 +50,7 @@ impl Context {
             reporter: Reporter::new(config.error_log.clone(), config.debug),
             config,
             lines_written: 0,
+            members: MemberBatch::default(),
         })
     }
 
@@ -74,22 +77,135 @@ impl Context {
                 );
             }
 
-            record.hash = match compute_hash(
-                Path::new(path),
-                size,
-                self.config.bytes,
-                self.config.algorithm,
-            ) {
-                Ok(hash) => hash,
+            let source = match record.source() {
+                Ok(source) => source,
                 Err(e) => {
                     self.reporter.report_error(&path.to_string(), e)?;
                     continue;
                 }
             };
+            if let Some(member) = source.member {
+                // Consecutive members of the same archive are hashed together,
+                // reading the archive only once.
+                if !self.members.accepts(&member) {
+                    self.hash_members(&mut writer)?;
+                }
+                self.members.push(member, record);
+                continue;
+            }
+            self.hash_members(&mut writer)?;
+            record.hash =
+                match compute_hash(&source, size, self.config.bytes, self.config.algorithm) {
+                    Ok(hash) => hash,
+                    Err(e) => {
+                        self.reporter.report_error(&path.to_string(), e)?;
+                        continue;
+                    }
+                };
+            record.whole_hash = self.hashes_whole(&record);
             writer.serialize(record)?;
             self.lines_written += 1;
         }
+        self.hash_members(&mut writer)?;
         println!();
         Ok(())
     }
+
+    fn hashes_whole(&self, record: &Record) -> bool {
+        self.config.bytes == 0 || record.size <= self.config.bytes
+    }
+
+    fn hash_members(&mut self, writer: &mut csv::Writer<File>) -> Result<()> {
+        let (bytes, algorithm) = (self.config.bytes, self.config.algorithm);
+        let hashed = self
+            .members
+            .read(|record: &mut Record, reader| hash_reader(reader, record.size, bytes, algorithm));
+        for (mut record, hash) in hashed {
+            match hash {
+                Ok(hash) => {
+                    record.hash = hash;
+                    record.whole_hash = self.hashes_whole(&record);
+                    writer.serialize(record)?;
+                    self.lines_written += 1;
+                }
+                Err(e) => self.reporter.report_error(&record.path, e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::archives::virtual_path;
+    use crate::common::Debug;
+
+    fn record(path: &std::path::Path, size: u64, in_archive: bool) -> Record {
+        Record {
+            in_archive,
+            ..Record::for_test(path.to_str().unwrap(), size, "NULL")
+        }
+    }
+
+    #[test]
+    fn test_members_are_hashed_in_input_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("a.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+        for (name, content) in &[("a.txt", "first"), ("b.txt", "second")] {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            std::io::Write::write_all(&mut zip, content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        let loose = dir.path().join("b.txt");
+        std::fs::write(&loose, "second").unwrap();
+        let source_file = dir.path().join("paths.csv");
+        let mut writer = csv::Writer::from_path(&source_file).unwrap();
+        let member = |name: &[u8]| virtual_path(&archive, name);
+        for record in [
+            record(&member(b"b.txt"), 6, true),
+            record(&member(b"missing"), 1, true),
+            record(&member(b"a.txt"), 5, true),
+            record(&loose, 6, false),
+            record(&member(b"a.txt"), 5, true),
+        ] {
+            writer.serialize(record).unwrap();
+        }
+        writer.flush().unwrap();
+        let target_file = dir.path().join("hashes.csv");
+        let mut ctx = Context::new(HashPathsConfig {
+            source_file,
+            target_file: target_file.clone(),
+            bytes: 0,
+            algorithm: HashAlgorithm::Md5,
+            show_progression: false,
+            debug: Debug::Off,
+            error_log: None,
+        })
+        .unwrap();
+        ctx.process().unwrap();
+        assert_eq!(ctx.reporter.error_count(), 1);
+        let actual: Vec<(String, String)> = csv::Reader::from_path(&target_file)
+            .unwrap()
+            .deserialize()
+            .map(|record| {
+                let record: Record = record.unwrap();
+                (record.path, record.hash)
+            })
+            .collect();
+        let first = "8b04d5e3775d298e78455efc5ca404d5".to_string();
+        let second = "a9f0e61a137d86aa9db53465e0801612".to_string();
+        let path = |path: PathBuf| path.to_str().unwrap().to_string();
+        assert_eq!(
+            actual,
+            vec![
+                (path(member(b"b.txt")), second.clone()),
+                (path(member(b"a.txt")), first.clone()),
+                (path(loose), second),
+                (path(member(b"a.txt")), first),
+            ]
+        );
+    }
 }
@@ -1,14 +1,19 @@
-use crate::common::{Debug, HashAlgorithm};
-use anyhow::Result;
+use crate::archives::{split_virtual_path, with_member, VirtualPath};
+use crate::common::{Debug, HashAlgorithm, SortOrder};
+use anyhow::{anyhow, Result};
 use digest::Digest;
 use md5::Md5;
 use sha1::Sha1;
 use sha2::Sha256;
 use sha2::Sha512;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::ffi::OsStr;
 use std::fmt::Write as _;
-use std::fs::File;
+use std::fs::{File, Metadata};
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use serde::{Deserialize, Serialize};
 
@@ -17,10 +22,227 @@ pub struct Record {
     pub path: String,
     pub size: u64,
     pub hash: String,
+    #[serde(default)]
+    pub modified: Option<i64>,
+    #[serde(default)]
+    pub dir_modified: Option<i64>,
+    #[serde(default)]
+    pub in_archive: bool,
+    // Set when the hash covers the whole file, as hash-paths can hash only
+    // the first bytes.
+    #[serde(default)]
+    pub whole_hash: bool,
+}
+
+// Paths that are not valid UTF-8 can't be stored as-is in the CSV files, so
+// they are stored behind this prefix with the invalid bytes escaped as \xNN
+// and backslashes escaped as \\. Any other path is stored unchanged.
+const OS_BYTES_PREFIX: &str = ":os-bytes:!";
+
+impl Record {
+    #[cfg(test)]
+    pub fn for_test(path: &str, size: u64, hash: &str) -> Record {
+        Record {
+            path: path.into(),
+            size,
+            hash: hash.into(),
+            modified: None,
+            dir_modified: None,
+            in_archive: false,
+            whole_hash: HashAlgorithm::of_hash(hash).is_some(),
+        }
+    }
+
+    pub fn os_path(&self) -> Result<PathBuf> {
+        decode_path(&self.path)
+    }
+
+    // The path as text for matching, with the escaped bytes of non UTF-8
+    // paths decoded and the invalid ones replaced.
+    pub fn text_path(&self) -> Cow<'_, str> {
+        if !self.path.starts_with(OS_BYTES_PREFIX) {
+            return Cow::Borrowed(&self.path);
+        }
+        match self.os_path() {
+            Ok(path) => Cow::Owned(path.to_string_lossy().into_owned()),
+            Err(_) => Cow::Borrowed(&self.path),
+        }
+    }
+
+    // Only files that gather-paths found inside archives are read from
+    // them, other paths are regular files even when they look like
+    // "a.zip!/b".
+    pub fn source(&self) -> Result<Source> {
+        let path = self.os_path()?;
+        let member = if self.in_archive {
+            Some(
+                split_virtual_path(&path)
+                    .ok_or_else(|| anyhow!("Path {:?} isn't inside an archive", path))?,
+            )
+        } else {
+            None
+        };
+        Ok(Source {
+            path,
+            member,
+            extracted: None,
+        })
+    }
+}
+
+// What the content of a file is read from: a regular file, or a file inside
+// an archive.
+pub trait AsSource {
+    fn path(&self) -> &Path;
+    fn member(&self) -> Option<&VirtualPath>;
+    fn extracted(&self) -> Option<&Path> {
+        None
+    }
+}
+
+impl AsSource for Path {
+    fn path(&self) -> &Path {
+        self
+    }
+    fn member(&self) -> Option<&VirtualPath> {
+        None
+    }
+}
+
+impl AsSource for PathBuf {
+    fn path(&self) -> &Path {
+        self
+    }
+    fn member(&self) -> Option<&VirtualPath> {
+        None
+    }
+}
+
+// Members read many times can be extracted to a temporary file first, which
+// is read instead of the archive.
+pub struct Source {
+    pub path: PathBuf,
+    pub member: Option<VirtualPath>,
+    pub extracted: Option<PathBuf>,
+}
+
+impl AsSource for Source {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+    fn member(&self) -> Option<&VirtualPath> {
+        self.member.as_ref()
+    }
+    fn extracted(&self) -> Option<&Path> {
+        self.extracted.as_deref()
+    }
+}
+
+// Opens either a regular file or a file inside an archive.
+pub fn with_source<T>(
+    source: &(impl AsSource + ?Sized),
+    f: impl FnOnce(&mut dyn Read) -> Result<T>,
+) -> Result<T> {
+    match (source.extracted(), source.member()) {
+        (None, Some(virtual_path)) => with_member(virtual_path, f),
+        (extracted, _) => f(&mut File::open(extracted.unwrap_or(source.path()))?),
+    }
+}
+
+pub fn copy_source(source: &(impl AsSource + ?Sized), target: &Path) -> Result<u64> {
+    match (source.extracted(), source.member()) {
+        (None, Some(_)) => with_source(source, |file| {
+            Ok(std::io::copy(file, &mut File::create(target)?)?)
+        }),
+        (extracted, _) => Ok(std::fs::copy(extracted.unwrap_or(source.path()), target)?),
+    }
+}
+
+#[cfg(unix)]
+pub fn encode_path(path: &Path) -> Result<String> {
+    use std::os::unix::ffi::OsStrExt;
+    let bytes = path.as_os_str().as_bytes();
+    if let Some(path) = path.to_str() {
+        if !path.starts_with(OS_BYTES_PREFIX) {
+            return Ok(path.into());
+        }
+    }
+    let mut encoded = String::with_capacity(OS_BYTES_PREFIX.len() + bytes.len());
+    encoded.push_str(OS_BYTES_PREFIX);
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c == '\\' {
+                encoded.push_str("\\\\");
+            } else {
+                encoded.push(c);
+            }
+        }
+        for byte in chunk.invalid() {
+            write!(&mut encoded, "\\x{:02x}", byte)?;
+        }
+    }
+    Ok(encoded)
+}
+
+#[cfg(not(unix))]
+pub fn encode_path(path: &Path) -> Result<String> {
+    Ok(path
+        .to_str()
+        .ok_or_else(|| anyhow!("Couldn't turn path into a str."))?
+        .into())
+}
+
+#[cfg(unix)]
+pub fn decode_path(path: &str) -> Result<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    if !path.starts_with(OS_BYTES_PREFIX) {
+        return Ok(PathBuf::from(path));
+    }
+    let escaped = &path.as_bytes()[OS_BYTES_PREFIX.len()..];
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut i = 0;
+    while i < escaped.len() {
+        if escaped[i] != b'\\' {
+            bytes.push(escaped[i]);
+            i += 1;
+            continue;
+        }
+        match escaped.get(i + 1) {
+            Some(b'\\') => {
+                bytes.push(b'\\');
+                i += 2;
+            }
+            Some(b'x') if i + 4 <= escaped.len() => {
+                let hex = std::str::from_utf8(&escaped[i + 2..i + 4])?;
+                bytes.push(u8::from_str_radix(hex, 16)?);
+                i += 4;
+            }
+            _ => return Err(anyhow!("Wrong escape sequence in path: {}", path)),
+        }
+    }
+    Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+#[cfg(not(unix))]
+pub fn decode_path(path: &str) -> Result<PathBuf> {
+    if path.starts_with(OS_BYTES_PREFIX) {
+        return Err(anyhow!("Can't decode raw bytes path: {}", path));
+    }
+    Ok(PathBuf::from(path))
 }
 
 pub fn compute_hash(
-    path: &Path,
+    path: &(impl AsSource + ?Sized),
+    file_size: u64,
+    batch_size: u64,
+    algo: HashAlgorithm,
+) -> Result<String> {
+    with_source(path, |file| hash_reader(file, file_size, batch_size, algo))
+}
+
+pub fn hash_reader(
+    file: &mut dyn Read,
     file_size: u64,
     batch_size: u64,
     algo: HashAlgorithm,
@@ -39,30 +261,32 @@ pub fn compute_hash(
         }
     };
     match algo {
-        HashAlgorithm::Sha1 => compute_hash_internal(path, size, Sha1::default()),
-        HashAlgorithm::Md5 => compute_hash_internal(path, size, Md5::default()),
-        HashAlgorithm::Sha256 => compute_hash_internal(path, size, Sha256::default()),
-        HashAlgorithm::Sha512 => compute_hash_internal(path, size, Sha512::default()),
+        HashAlgorithm::Sha1 => compute_hash_internal(file, size, Sha1::default()),
+        HashAlgorithm::Md5 => compute_hash_internal(file, size, Md5::default()),
+        HashAlgorithm::Sha256 => compute_hash_internal(file, size, Sha256::default()),
+        HashAlgorithm::Sha512 => compute_hash_internal(file, size, Sha512::default()),
     }
 }
 
-fn compute_hash_internal(path: &Path, size: usize, mut sh: impl Digest) -> Result<String> {
-    let mut file = File::open(&path)?;
-    let len = file.metadata()?.len();
+// The hash of the record when it is of the whole file, otherwise the md5 of
+// the file.
+pub fn record_hash(path: &(impl AsSource + ?Sized), record: &Record) -> Result<String> {
+    match HashAlgorithm::of_hash(&record.hash) {
+        Some(_) if record.whole_hash => Ok(record.hash.clone()),
+        _ => compute_hash(path, record.size, 0, HashAlgorithm::Md5),
+    }
+}
+
+fn compute_hash_internal(file: &mut dyn Read, size: usize, mut sh: impl Digest) -> Result<String> {
     if size == 0 {
         const BUFFER_SIZE: usize = 64768;
-        let mut readed: u64 = 0;
         let mut buffer = [0u8; BUFFER_SIZE];
         loop {
             let n = file.read(&mut buffer)?;
-            readed += n as u64;
-            sh.input(&buffer[..n]);
-            if n == 0 || n < BUFFER_SIZE {
-                if readed != len {
-                    panic!("Readed and len not matching ({} != {})", readed, len);
-                }
+            if n == 0 {
                 break;
             }
+            sh.input(&buffer[..n]);
         }
     } else {
         let mut buffer = vec![0; size];
@@ -77,6 +301,95 @@ fn compute_hash_internal(path: &Path, size: usize, mut sh: impl Digest) -> Resul
     Ok(hash)
 }
 
+pub fn compare_names(order: SortOrder, a: &OsStr, b: &OsStr) -> Ordering {
+    compare_bytes(order, a.as_encoded_bytes(), b.as_encoded_bytes())
+}
+
+pub fn compare_bytes(order: SortOrder, a: &[u8], b: &[u8]) -> Ordering {
+    match order {
+        SortOrder::ByteWise => a.cmp(b),
+        SortOrder::Natural => compare_natural(a, b).then_with(|| a.cmp(b)),
+        SortOrder::CaseInsensitive => a
+            .iter()
+            .map(u8::to_ascii_lowercase)
+            .cmp(b.iter().map(u8::to_ascii_lowercase))
+            .then_with(|| a.cmp(b)),
+    }
+}
+
+// Runs of digits are compared by their numeric value, so "2.jpg" goes before
+// "10.jpg". Everything else is compared byte by byte.
+fn compare_natural(mut a: &[u8], mut b: &[u8]) -> Ordering {
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (digits_a, rest_a) = split_digits(a);
+                let (digits_b, rest_b) = split_digits(b);
+                let number_a = trim_zeros(digits_a);
+                let number_b = trim_zeros(digits_b);
+                let ordering = number_a
+                    .len()
+                    .cmp(&number_b.len())
+                    .then_with(|| number_a.cmp(number_b));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a = rest_a;
+                b = rest_b;
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                a = &a[1..];
+                b = &b[1..];
+            }
+        }
+    }
+}
+
+fn split_digits(s: &[u8]) -> (&[u8], &[u8]) {
+    let end = s
+        .iter()
+        .position(|c| !c.is_ascii_digit())
+        .unwrap_or(s.len());
+    s.split_at(end)
+}
+
+fn trim_zeros(s: &[u8]) -> &[u8] {
+    let start = s.iter().position(|c| *c != b'0').unwrap_or(s.len());
+    &s[start..]
+}
+
+pub fn modified_time(metadata: &Metadata) -> Option<i64> {
+    metadata.modified().ok().map(unix_time)
+}
+
+pub fn unix_time(time: SystemTime) -> i64 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+#[cfg(unix)]
+pub fn device_id(metadata: &Metadata) -> Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(metadata.dev())
+}
+
+// Stable Rust has no way to tell the volume of a file elsewhere, so staying
+// on one file system is refused rather than silently ignored.
+#[cfg(not(unix))]
+pub fn device_id(_metadata: &Metadata) -> Result<u64> {
+    Err(anyhow!(
+        "Staying on one file system is only supported on unix."
+    ))
+}
+
 pub struct Reporter {
     errors_path: Option<PathBuf>,
     errors_file: Option<File>,
@@ -125,6 +438,98 @@ impl Reporter {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_only_records_from_archives_are_read_from_them() {
+        let mut record = Record::for_test("/backup/x.zip!/photos/img.jpg", 1, "NULL");
+        let source = record.source().unwrap();
+        assert_eq!(source.path, Path::new("/backup/x.zip!/photos/img.jpg"));
+        assert!(source.member.is_none());
+        record.in_archive = true;
+        let member = record.source().unwrap().member.unwrap();
+        assert_eq!(member.archive, Path::new("/backup/x.zip"));
+        assert_eq!(member.member, b"photos/img.jpg");
+        record.path = "/backup/photos/img.jpg".into();
+        assert!(record.source().is_err());
+    }
+
+    #[test]
+    fn test_utf8_path_is_encoded_as_is() {
+        let actual = encode_path(Path::new("/mnt/c/Música/01 - Oihu.mp3")).unwrap();
+        assert_eq!(actual, "/mnt/c/Música/01 - Oihu.mp3");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_path_is_escaped() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"/cd/M\xfasica\\01.mp3"));
+        let actual = encode_path(path).unwrap();
+        assert_eq!(actual, ":os-bytes:!/cd/M\\xfasica\\\\01.mp3");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_path_roundtrip() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"/cd/\x83\x5c\\x41/\xff.jpg"));
+        let actual = decode_path(&encode_path(path).unwrap()).unwrap();
+        assert_eq!(actual, path);
+    }
+
+    fn sorted(order: SortOrder, names: &[&str]) -> Vec<String> {
+        let mut names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        names.sort_by(|a, b| compare_names(order, OsStr::new(a), OsStr::new(b)));
+        names
+    }
+
+    #[test]
+    fn test_bytewise_sort() {
+        let actual = sorted(SortOrder::ByteWise, &["b", "B", "a10", "a2"]);
+        assert_eq!(actual, vec!["B", "a10", "a2", "b"]);
+    }
+
+    #[test]
+    fn test_natural_sort() {
+        let actual = sorted(
+            SortOrder::Natural,
+            &[
+                "IMG_10.jpg",
+                "IMG_2.jpg",
+                "IMG_002.jpg",
+                "IMG_1.jpg",
+                "IMG.jpg",
+            ],
+        );
+        assert_eq!(
+            actual,
+            vec![
+                "IMG.jpg",
+                "IMG_1.jpg",
+                "IMG_002.jpg",
+                "IMG_2.jpg",
+                "IMG_10.jpg"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_case_insensitive_sort() {
+        let actual = sorted(SortOrder::CaseInsensitive, &["b", "a", "B", "A"]);
+        assert_eq!(actual, vec!["A", "a", "B", "b"]);
+    }
+
+    #[test]
+    fn test_prefixed_utf8_path_roundtrip() {
+        let path = Path::new(":os-bytes:!\\x41");
+        let actual = decode_path(&encode_path(path).unwrap()).unwrap();
+        assert_eq!(actual, path);
+    }
+}
+
 /*
 use chrono::{TimeZone, Utc};
 use std::time::{Instant, SystemTime};
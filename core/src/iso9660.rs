@@ -0,0 +1,342 @@
+use anyhow::{anyhow, Result};
+use chrono::NaiveDate;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+const SECTOR_SIZE: u64 = 2048;
+const FIRST_DESCRIPTOR_SECTOR: u64 = 16;
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub name: Vec<u8>,
+    pub size: u64,
+    pub modified: Option<i64>,
+    extent: u64,
+    is_dir: bool,
+}
+
+// Read-only access to ISO 9660 disc images, preferring Joliet names when the
+// image has them. UDF-only images are rejected.
+pub struct Image {
+    file: File,
+    len: u64,
+    root: Entry,
+    joliet: bool,
+}
+
+impl Image {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut primary = None;
+        let mut joliet = None;
+        let mut udf = false;
+        for sector in FIRST_DESCRIPTOR_SECTOR.. {
+            let mut descriptor = [0u8; SECTOR_SIZE as usize];
+            file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+            if file.read_exact(&mut descriptor).is_err() {
+                break;
+            }
+            match &descriptor[1..6] {
+                b"CD001" => {}
+                b"BEA01" | b"NSR02" | b"NSR03" | b"TEA01" => {
+                    udf = true;
+                    continue;
+                }
+                _ => break,
+            }
+            match descriptor[0] {
+                1 if primary.is_none() => primary = Some(parse_record(&descriptor[156..190])?),
+                2 if is_joliet(&descriptor) => joliet = Some(parse_record(&descriptor[156..190])?),
+                255 => break,
+                _ => {}
+            }
+        }
+        let (root, joliet) = match (joliet, primary) {
+            (Some(root), _) => (root, true),
+            (None, Some(root)) => (root, false),
+            (None, None) if udf => return Err(anyhow!("UDF-only disc images are not supported.")),
+            (None, None) => return Err(anyhow!("Not an ISO 9660 disc image.")),
+        };
+        Ok(Image {
+            file,
+            len,
+            root,
+            joliet,
+        })
+    }
+
+    // Returns every file in the image along with its path inside the image.
+    pub fn files(&mut self) -> Result<Vec<(Vec<u8>, Entry)>> {
+        let mut files = Vec::new();
+        let mut visited = HashSet::new();
+        let mut pending = vec![(Vec::new(), self.root.clone())];
+        while let Some((prefix, dir)) = pending.pop() {
+            if !visited.insert(dir.extent) {
+                continue;
+            }
+            for entry in self.read_dir(&dir)? {
+                let mut path = prefix.clone();
+                if !path.is_empty() {
+                    path.push(b'/');
+                }
+                path.extend_from_slice(&entry.name);
+                if entry.is_dir {
+                    pending.push((path, entry));
+                } else {
+                    files.push((path, entry));
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    pub fn find(&mut self, path: &[u8]) -> Result<Entry> {
+        let mut current = self.root.clone();
+        for component in path.split(|c| *c == b'/').filter(|c| !c.is_empty()) {
+            current = self
+                .read_dir(&current)?
+                .into_iter()
+                .find(|entry| entry.name == component)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "File {:?} not found in disc image.",
+                        String::from_utf8_lossy(path)
+                    )
+                })?;
+        }
+        if current.is_dir {
+            return Err(anyhow!(
+                "{:?} is a folder in the disc image.",
+                String::from_utf8_lossy(path)
+            ));
+        }
+        Ok(current)
+    }
+
+    pub fn reader(&mut self, entry: &Entry) -> Result<impl Read + '_> {
+        self.file
+            .seek(SeekFrom::Start(entry.extent * SECTOR_SIZE))?;
+        Ok((&mut self.file).take(entry.size))
+    }
+
+    // Folder sizes come from the image, so they are checked against its
+    // length before reading, and a corrupted one can't take all the memory.
+    fn read_dir(&mut self, dir: &Entry) -> Result<Vec<Entry>> {
+        let start = dir.extent * SECTOR_SIZE;
+        if start + dir.size > self.len {
+            return Err(anyhow!("Folder beyond the end of the disc image."));
+        }
+        let mut data = Vec::new();
+        self.file.seek(SeekFrom::Start(start))?;
+        (&mut self.file).take(dir.size).read_to_end(&mut data)?;
+        if data.len() as u64 != dir.size {
+            return Err(anyhow!("Folder beyond the end of the disc image."));
+        }
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let len = data[offset] as usize;
+            if len == 0 {
+                // Records never cross sector boundaries, the rest is padding.
+                offset = (offset / SECTOR_SIZE as usize + 1) * SECTOR_SIZE as usize;
+                continue;
+            }
+            if offset + len > data.len() {
+                return Err(anyhow!("Corrupted directory record in disc image."));
+            }
+            let mut entry = parse_record(&data[offset..offset + len])?;
+            offset += len;
+            // "." and ".." are stored as the single bytes 0 and 1.
+            if entry.name == [0] || entry.name == [1] {
+                continue;
+            }
+            entry.name = self.decode_name(&entry.name, entry.is_dir);
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    fn decode_name(&self, name: &[u8], is_dir: bool) -> Vec<u8> {
+        let mut name = if self.joliet {
+            let units: Vec<u16> = name
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16_lossy(&units).into_bytes()
+        } else {
+            name.to_vec()
+        };
+        if !is_dir {
+            if let Some(position) = name.iter().rposition(|c| *c == b';') {
+                name.truncate(position);
+            }
+            if name.last() == Some(&b'.') {
+                name.pop();
+            }
+        }
+        name
+    }
+}
+
+fn is_joliet(descriptor: &[u8]) -> bool {
+    matches!(&descriptor[88..91], b"%/@" | b"%/C" | b"%/E")
+}
+
+fn parse_record(record: &[u8]) -> Result<Entry> {
+    if record.len() < 34 || record.len() < 33 + record[32] as usize {
+        return Err(anyhow!("Corrupted directory record in disc image."));
+    }
+    let name_len = record[32] as usize;
+    Ok(Entry {
+        name: record[33..33 + name_len].to_vec(),
+        size: u32::from_le_bytes([record[10], record[11], record[12], record[13]]).into(),
+        modified: parse_date(&record[18..25]),
+        extent: u32::from_le_bytes([record[2], record[3], record[4], record[5]]).into(),
+        is_dir: record[25] & 0x02 != 0,
+    })
+}
+
+fn parse_date(date: &[u8]) -> Option<i64> {
+    let time = NaiveDate::from_ymd_opt(1900 + date[0] as i32, date[1].into(), date[2].into())?
+        .and_hms_opt(date[3].into(), date[4].into(), date[5].into())?;
+    // The last byte is the offset from GMT in 15 minutes intervals.
+    Some(time.and_utc().timestamp() - (date[6] as i8) as i64 * 15 * 60)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_date_applies_gmt_offset() {
+        let actual = parse_date(&[99, 6, 15, 12, 30, 0, 8]);
+        assert_eq!(actual, Some(929_442_600));
+    }
+
+    #[test]
+    fn test_parse_record_of_file() {
+        let mut record = vec![0u8; 33];
+        record[0] = 45;
+        record[2] = 23;
+        record[10] = 6;
+        record[32] = 12;
+        record.extend_from_slice(b"README.TXT;1");
+        let actual = parse_record(&record).unwrap();
+        assert_eq!(actual.name, b"README.TXT;1");
+        assert_eq!(actual.extent, 23);
+        assert_eq!(actual.size, 6);
+        assert!(!actual.is_dir);
+    }
+
+    fn directory_record(name: &[u8], extent: u32, size: u32, is_dir: bool) -> Vec<u8> {
+        let mut record = vec![0u8; 33];
+        record[0] = (33 + name.len() + (name.len() + 1) % 2) as u8;
+        record[2..6].copy_from_slice(&extent.to_le_bytes());
+        record[6..10].copy_from_slice(&extent.to_be_bytes());
+        record[10..14].copy_from_slice(&size.to_le_bytes());
+        record[14..18].copy_from_slice(&size.to_be_bytes());
+        record[18..25].copy_from_slice(&[120, 1, 2, 3, 4, 5, 0]);
+        record[25] = if is_dir { 2 } else { 0 };
+        record[32] = name.len() as u8;
+        record.extend_from_slice(name);
+        record.resize(record[0] as usize, 0);
+        record
+    }
+
+    // Descriptors in sectors 16 and 17, the root folder in 18, a DOCS folder
+    // in 19 and the content of its files after that.
+    fn generate_image(path: &Path) {
+        let sector = |number: usize| number * SECTOR_SIZE as usize;
+        let mut image = vec![0u8; sector(22)];
+        image[sector(16)] = 1;
+        image[sector(16) + 1..sector(16) + 6].copy_from_slice(b"CD001");
+        let root = directory_record(&[0], 18, SECTOR_SIZE as u32, true);
+        image[sector(16) + 156..sector(16) + 190].copy_from_slice(&root);
+        image[sector(17)] = 255;
+        image[sector(17) + 1..sector(17) + 6].copy_from_slice(b"CD001");
+        let folders = [
+            (
+                18,
+                vec![
+                    directory_record(&[0], 18, SECTOR_SIZE as u32, true),
+                    directory_record(&[1], 18, SECTOR_SIZE as u32, true),
+                    directory_record(b"DOCS", 19, SECTOR_SIZE as u32, true),
+                    directory_record(b"README.TXT;1", 20, 6, false),
+                ],
+            ),
+            (
+                19,
+                vec![
+                    directory_record(&[0], 19, SECTOR_SIZE as u32, true),
+                    directory_record(&[1], 18, SECTOR_SIZE as u32, true),
+                    directory_record(b"A.TXT;1", 21, 5, false),
+                ],
+            ),
+        ];
+        for (number, records) in folders.iter() {
+            let start = sector(*number);
+            let records = records.concat();
+            image[start..start + records.len()].copy_from_slice(&records);
+        }
+        image[sector(20)..sector(20) + 6].copy_from_slice(b"hello\n");
+        image[sector(21)..sector(21) + 5].copy_from_slice(b"inner");
+        std::fs::write(path, image).unwrap();
+    }
+
+    #[test]
+    fn test_read_file_from_generated_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disc.iso");
+        generate_image(&path);
+        let mut image = Image::open(&path).unwrap();
+        let mut files: Vec<(Vec<u8>, u64)> = image
+            .files()
+            .unwrap()
+            .into_iter()
+            .map(|(name, entry)| (name, entry.size))
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![(b"DOCS/A.TXT".to_vec(), 5), (b"README.TXT".to_vec(), 6)]
+        );
+        let entry = image.find(b"DOCS/A.TXT").unwrap();
+        assert_eq!(entry.modified, Some(1_577_934_245));
+        let mut content = String::new();
+        image
+            .reader(&entry)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "inner");
+        assert!(image.find(b"DOCS").is_err());
+    }
+
+    #[test]
+    fn test_truncated_image_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disc.iso");
+        generate_image(&path);
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(19 * SECTOR_SIZE + 100).unwrap();
+        let mut image = Image::open(&path).unwrap();
+        assert!(image.files().is_err());
+        assert!(image.find(b"DOCS/A.TXT").is_err());
+        assert_eq!(image.find(b"README.TXT").unwrap().size, 6);
+    }
+
+    #[test]
+    fn test_huge_folder_size_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disc.iso");
+        generate_image(&path);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let size = 16 * SECTOR_SIZE as usize + 156 + 10;
+        bytes[size..size + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, bytes).unwrap();
+        assert!(Image::open(&path).unwrap().files().is_err());
+    }
+}
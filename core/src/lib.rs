@@ -1,9 +1,18 @@
+mod archives;
+mod categories;
 pub mod common;
 pub mod copy_files;
 pub mod detect_dups;
+mod exif_date;
+mod external_sort;
+mod file_types;
+pub mod filter_expr;
 pub mod filter_paths;
 pub mod gather_paths;
 pub mod hash_paths;
 mod internals;
+mod iso9660;
+pub mod path_template;
+pub mod restore_files;
 pub mod single_hash;
 pub mod unique_paths;
@@ -0,0 +1,254 @@
+use crate::internals::{record_hash, Record, Source};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, TimeZone};
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+
+// Layout of the copied files inside the output folder, for example:
+//   {year}/{month}/{stem}{ext}
+//
+// Variables: year, month, day (of the modification date), name, stem,
+// ext (with the dot), extension (without it), hash, hash:N (its first N
+// characters), parent (name of the source folder) and path (the whole
+// source path). Slashes create folders.
+#[derive(Debug, Clone)]
+pub struct PathTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Var(Var),
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Var {
+    Year,
+    Month,
+    Day,
+    Name,
+    Stem,
+    Ext,
+    Extension,
+    Hash(Option<usize>),
+    Parent,
+    Path,
+}
+
+impl std::str::FromStr for PathTemplate {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].into()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("Missing '}}' in template '{}'.", s))?;
+            parts.push(Part::Var(parse_var(&rest[start + 1..start + end])?));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.into()));
+        }
+        if parts.is_empty() {
+            return Err("The template can't be empty.".into());
+        }
+        Ok(PathTemplate { parts })
+    }
+}
+
+fn parse_var(name: &str) -> Result<Var, String> {
+    Ok(match name {
+        "year" => Var::Year,
+        "month" => Var::Month,
+        "day" => Var::Day,
+        "name" => Var::Name,
+        "stem" => Var::Stem,
+        "ext" => Var::Ext,
+        "extension" => Var::Extension,
+        "hash" => Var::Hash(None),
+        "parent" => Var::Parent,
+        "path" => Var::Path,
+        _ => match name.strip_prefix("hash:").map(str::parse) {
+            Some(Ok(len)) => Var::Hash(Some(len)),
+            _ => return Err(format!(
+                "No template variable named '{}', try these instead: year, month, day, name, stem, ext, extension, hash, hash:N, parent, path.",
+                name
+            )),
+        },
+    })
+}
+
+// The file being copied. The relative path is the one used by {path}.
+pub struct TemplateSource<'a> {
+    pub source: &'a Source,
+    pub relative_path: &'a Path,
+    pub record: &'a Record,
+}
+
+impl PathTemplate {
+    pub(crate) fn uses_date(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Var(Var::Year | Var::Month | Var::Day)))
+    }
+
+    // The date is only asked for when the template uses it.
+    pub(crate) fn render(
+        &self,
+        source: &TemplateSource,
+        mut date: impl FnMut() -> Result<i64>,
+    ) -> Result<PathBuf> {
+        let path = &source.source.path;
+        let mut rendered = OsString::new();
+        let mut date_time: Option<DateTime<Local>> = None;
+        let mut hash: Option<String> = None;
+        for part in &self.parts {
+            let var = match part {
+                Part::Literal(text) => {
+                    rendered.push(text);
+                    continue;
+                }
+                Part::Var(var) => *var,
+            };
+            match var {
+                Var::Year | Var::Month | Var::Day => {
+                    if date_time.is_none() {
+                        let timestamp = date()?;
+                        date_time = Some(
+                            Local
+                                .timestamp_opt(timestamp, 0)
+                                .single()
+                                .ok_or_else(|| anyhow!("Wrong date: {}", timestamp))?,
+                        );
+                    }
+                    let format = match var {
+                        Var::Year => "%Y",
+                        Var::Month => "%m",
+                        _ => "%d",
+                    };
+                    rendered.push(date_time.unwrap().format(format).to_string());
+                }
+                Var::Name => rendered.push(path.file_name().unwrap_or_default()),
+                Var::Stem => rendered.push(path.file_stem().unwrap_or_default()),
+                Var::Ext | Var::Extension => {
+                    if let Some(extension) = path.extension() {
+                        if let Var::Ext = var {
+                            rendered.push(".");
+                        }
+                        rendered.push(extension);
+                    }
+                }
+                Var::Hash(len) => {
+                    if hash.is_none() {
+                        hash = Some(record_hash(source.source, source.record)?);
+                    }
+                    let hash = hash.as_deref().unwrap();
+                    rendered.push(&hash[..len.unwrap_or(hash.len()).min(hash.len())]);
+                }
+                Var::Parent => {
+                    if let Some(parent) = path.parent().and_then(|parent| parent.file_name()) {
+                        rendered.push(parent);
+                    }
+                }
+                Var::Path => rendered.push(source.relative_path),
+            }
+        }
+        // The result always stays inside the output folder.
+        let mut path = PathBuf::new();
+        for component in Path::new(&rendered).components() {
+            match component {
+                Component::Normal(name) => path.push(name),
+                Component::RootDir | Component::CurDir => {}
+                _ => return Err(anyhow!("Template gave a wrong path: {:?}", rendered)),
+            }
+        }
+        if path.as_os_str().is_empty() {
+            return Err(anyhow!("Template gave an empty path"));
+        }
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::Timestamp;
+
+    fn render(template: &str, path: &str, hash: &str) -> String {
+        let record = Record::for_test(path, 1, hash);
+        let file = Source {
+            path: path.into(),
+            member: None,
+            extracted: None,
+        };
+        let source = TemplateSource {
+            source: &file,
+            relative_path: Path::new(path.trim_start_matches('/')),
+            record: &record,
+        };
+        let date = "2019-06-05 18:30".parse::<Timestamp>().unwrap().0;
+        let template: PathTemplate = template.parse().unwrap();
+        let rendered = template.render(&source, || Ok(date)).unwrap();
+        rendered.to_str().unwrap().into()
+    }
+
+    #[test]
+    fn test_date_folders() {
+        assert_eq!(
+            render("{year}/{month}/{day}/{stem}{ext}", "/a/b/c.jpg", "NULL"),
+            "2019/06/05/c.jpg"
+        );
+    }
+
+    #[test]
+    fn test_name_parts() {
+        assert_eq!(
+            render("{extension}/{name}", "/a/b/c.mp3", "NULL"),
+            "mp3/c.mp3"
+        );
+        assert_eq!(render("{parent} - {stem}", "/a/b/c.mp3", "NULL"), "b - c");
+        assert_eq!(
+            render("backup/{path}", "/a/b/c.mp3", "NULL"),
+            "backup/a/b/c.mp3"
+        );
+    }
+
+    #[test]
+    fn test_hash_prefix() {
+        let hash = "0123456789abcdef0123456789abcdef";
+        assert_eq!(
+            render("{hash:2}/{hash}", "/a/b/c.mp3", hash),
+            format!("01/{}", hash)
+        );
+    }
+
+    #[test]
+    fn test_paths_stay_inside_the_output_folder() {
+        let template: PathTemplate = "../{name}".parse().unwrap();
+        let record = Record::for_test("/a.jpg", 1, "NULL");
+        let file = Source {
+            path: "/a.jpg".into(),
+            member: None,
+            extracted: None,
+        };
+        let source = TemplateSource {
+            source: &file,
+            relative_path: Path::new("a.jpg"),
+            record: &record,
+        };
+        assert!(template.render(&source, || Ok(0)).is_err());
+    }
+
+    #[test]
+    fn test_wrong_templates() {
+        assert!("{year".parse::<PathTemplate>().is_err());
+        assert!("{size}".parse::<PathTemplate>().is_err());
+        assert!("{hash:x}".parse::<PathTemplate>().is_err());
+        assert!("".parse::<PathTemplate>().is_err());
+    }
+}
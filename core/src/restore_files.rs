@@ -0,0 +1,328 @@
+use crate::common::{Debug, HashAlgorithm, SizeFormat};
+use crate::copy_files::{object_path, partial_path, STORE_INDEX};
+use crate::internals::{compute_hash, Record, Reporter};
+use anyhow::{anyhow, Result};
+use num_format::{Locale, ToFormattedString};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, FileTimes, OpenOptions};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Debug, Default)]
+pub struct RestoreFilesConfig {
+    pub store: PathBuf,
+    // A subset of the store index, like the output of filter-paths.
+    pub index_file: Option<PathBuf>,
+    pub target_folder: PathBuf,
+    pub strip_prefix: Option<PathBuf>,
+    pub hardlinks: bool,
+    pub verify: bool,
+    pub show_progression: bool,
+    pub debug: Debug,
+    pub error_log: Option<PathBuf>,
+    pub size_format: SizeFormat,
+}
+
+pub fn restore_files(config: RestoreFilesConfig) -> Result<()> {
+    println!("RESTORE FILES | config: {:?}", config);
+    let now = Instant::now();
+    let mut ctx = Context::new(config)?;
+    ctx.process()?;
+    println!("Duration: {:#?}", (Instant::now() - now));
+    println!(
+        "Restored {} files {:?}",
+        ctx.lines_written.to_formatted_string(&Locale::en),
+        ctx.config.target_folder
+    );
+    if ctx.config.hardlinks {
+        println!(
+            "Hardlinked {} of them",
+            ctx.hardlinked.to_formatted_string(&Locale::en)
+        );
+    }
+    println!(
+        "Disk space taken: {}",
+        ctx.config.size_format.format(ctx.copied_size)
+    );
+    println!(
+        "Errors: {} ({:?})",
+        ctx.reporter.error_count().to_formatted_string(&Locale::en),
+        ctx.config.error_log
+    );
+    Ok(())
+}
+
+struct Context {
+    config: RestoreFilesConfig,
+    reporter: Reporter,
+    // Objects already hashed, hardlinks to them don't need to be checked again.
+    verified: HashSet<String>,
+    lines_written: u64,
+    hardlinked: u64,
+    copied_size: u64,
+}
+
+impl Context {
+    pub fn new(config: RestoreFilesConfig) -> Result<Self> {
+        Ok(Context {
+            reporter: Reporter::new(config.error_log.clone(), config.debug),
+            config,
+            verified: HashSet::new(),
+            lines_written: 0,
+            hardlinked: 0,
+            copied_size: 0,
+        })
+    }
+
+    // The index is only appended to, so paths backed up several times are
+    // restored once, from their latest backup.
+    pub fn process(&mut self) -> Result<()> {
+        let index_file = self
+            .config
+            .index_file
+            .clone()
+            .unwrap_or_else(|| self.config.store.join(STORE_INDEX));
+        let mut latest = HashMap::new();
+        let mut reader = csv::Reader::from_reader(File::open(&index_file)?);
+        for (line, record) in reader.deserialize().enumerate() {
+            let record: Record = record?;
+            latest.insert(record.path, line);
+        }
+        let total = latest.len();
+        std::fs::create_dir(&self.config.target_folder)?;
+        let mut reader = csv::Reader::from_reader(File::open(&index_file)?);
+        let mut current = 0;
+        for (line, record) in reader.deserialize().enumerate() {
+            let record: Record = record?;
+            if latest.get(&record.path) != Some(&line) {
+                continue;
+            }
+            if self.config.show_progression {
+                current += 1;
+                print!("\r{:.2}%        ", (current as f64 / total as f64) * 100.0);
+            }
+            match self.restore(&record) {
+                Ok(()) => self.lines_written += 1,
+                Err(e) => self.reporter.report_error(&record.path, e)?,
+            }
+        }
+        println!();
+        Ok(())
+    }
+
+    fn restore(&mut self, record: &Record) -> Result<()> {
+        let algorithm = HashAlgorithm::of_hash(&record.hash)
+            .ok_or_else(|| anyhow!("Wrong hash in the index: {}", record.hash))?;
+        let object_path = object_path(&self.config.store, &record.hash)?;
+        let target_path = self.config.target_folder.join(self.relative_path(record)?);
+        std::fs::create_dir_all(
+            target_path
+                .parent()
+                .ok_or_else(|| anyhow!("Parent should be a dir"))?,
+        )?;
+        if let Debug::On = self.config.debug {
+            print!("Restoring {:?} to {:?}", object_path, target_path);
+        }
+        if self.config.hardlinks && self.hardlink(&object_path, &target_path, record, algorithm)? {
+            self.hardlinked += 1;
+            return Ok(());
+        }
+        let partial_path = partial_path(&target_path)?;
+        let size = std::fs::copy(&object_path, &partial_path)?;
+        if self.config.verify {
+            let hash = compute_hash(&partial_path, size, 0, algorithm)?;
+            if hash != record.hash {
+                std::fs::remove_file(&partial_path)?;
+                return Err(anyhow!("Object {:?} has hash {}", object_path, hash));
+            }
+        }
+        // Hardlinks share the timestamps of their object, only copies get
+        // their own.
+        if let Some(modified) = record.modified.filter(|modified| *modified >= 0) {
+            let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(modified as u64);
+            OpenOptions::new()
+                .write(true)
+                .open(&partial_path)?
+                .set_times(FileTimes::new().set_modified(modified))?;
+        }
+        std::fs::rename(&partial_path, &target_path)?;
+        self.copied_size += size;
+        Ok(())
+    }
+
+    // Returns false when the object can't be linked, like when the target
+    // is in another filesystem, so it's copied instead.
+    fn hardlink(
+        &mut self,
+        object_path: &Path,
+        target_path: &Path,
+        record: &Record,
+        algorithm: HashAlgorithm,
+    ) -> Result<bool> {
+        if self.config.verify && !self.verified.contains(&record.hash) {
+            let size = std::fs::metadata(object_path)?.len();
+            let hash = compute_hash(object_path, size, 0, algorithm)?;
+            if hash != record.hash {
+                return Err(anyhow!("Object {:?} has hash {}", object_path, hash));
+            }
+            self.verified.insert(hash);
+        }
+        if target_path.exists() {
+            std::fs::remove_file(target_path)?;
+        }
+        // Other devices, filesystems without hardlinks or objects with too
+        // many links get a copy instead.
+        Ok(std::fs::hard_link(object_path, target_path).is_ok())
+    }
+
+    // The original path without its root, or without the stripped prefix.
+    // The result always stays inside the output folder.
+    fn relative_path(&self, record: &Record) -> Result<PathBuf> {
+        let path = record.os_path()?;
+        let stripped = match &self.config.strip_prefix {
+            Some(prefix) => path
+                .strip_prefix(prefix)
+                .map_err(|_| anyhow!("Path isn't inside the stripped prefix {:?}", prefix))?,
+            None => &path,
+        };
+        let mut relative = PathBuf::new();
+        for component in stripped.components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::Prefix(_) | Component::RootDir if relative.as_os_str().is_empty() => {}
+                Component::CurDir => {}
+                _ => return Err(anyhow!("Can't restore {:?} inside the output folder", path)),
+            }
+        }
+        if relative.as_os_str().is_empty() {
+            return Err(anyhow!("Can't restore {:?} inside the output folder", path));
+        }
+        Ok(relative)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn store_object(store: &Path, hash: &str, content: &str) {
+        let object_path = object_path(store, hash).unwrap();
+        std::fs::create_dir_all(object_path.parent().unwrap()).unwrap();
+        std::fs::write(object_path, content).unwrap();
+    }
+
+    #[test]
+    fn test_restore_tree_from_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = dir.path().join("store");
+        // md5 of "same", and a corrupt object
+        store_object(&store, "51037a4a37730f52c8732586d3aaa316", "same");
+        store_object(&store, "00000000000000000000000000000000", "corrupt");
+        std::fs::write(
+            store.join(STORE_INDEX),
+            "path,size,hash,modified,dir_modified\n\
+             /data/a/x.txt,4,51037a4a37730f52c8732586d3aaa316,1500000000,\n\
+             /data/b/y.txt,4,51037a4a37730f52c8732586d3aaa316,1500000000,\n\
+             /data/c.txt,7,00000000000000000000000000000000,,\n",
+        )
+        .unwrap();
+        let target_folder = dir.path().join("out");
+        let config = RestoreFilesConfig {
+            store,
+            target_folder: target_folder.clone(),
+            strip_prefix: Some(PathBuf::from("/data")),
+            verify: true,
+            ..RestoreFilesConfig::default()
+        };
+        let mut ctx = Context::new(config).unwrap();
+        ctx.process().unwrap();
+        assert_eq!(ctx.lines_written, 2);
+        assert_eq!(ctx.reporter.error_count(), 1);
+        let restored = target_folder.join("a/x.txt");
+        assert_eq!(std::fs::read_to_string(&restored).unwrap(), "same");
+        assert_eq!(
+            std::fs::metadata(&restored).unwrap().modified().unwrap(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000)
+        );
+        assert!(target_folder.join("b/y.txt").exists());
+        assert!(!target_folder.join("c.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_with_hardlinks() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = tempfile::tempdir().unwrap();
+        let store = dir.path().join("store");
+        store_object(&store, "51037a4a37730f52c8732586d3aaa316", "same");
+        std::fs::write(
+            store.join(STORE_INDEX),
+            "path,size,hash\n/a.txt,4,51037a4a37730f52c8732586d3aaa316\n",
+        )
+        .unwrap();
+        let config = RestoreFilesConfig {
+            store: store.clone(),
+            target_folder: dir.path().join("out"),
+            hardlinks: true,
+            ..RestoreFilesConfig::default()
+        };
+        let mut ctx = Context::new(config).unwrap();
+        ctx.process().unwrap();
+        assert_eq!(ctx.hardlinked, 1);
+        let object = object_path(&store, "51037a4a37730f52c8732586d3aaa316").unwrap();
+        assert_eq!(std::fs::metadata(object).unwrap().nlink(), 2);
+    }
+
+    #[test]
+    fn test_paths_backed_up_twice_are_restored_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = dir.path().join("store");
+        // md5 of "old" and "new"
+        store_object(&store, "149603e6c03516362a8da23f624db945", "old");
+        store_object(&store, "22af645d1859cb5ca6da0c484f1f37ea", "new");
+        std::fs::write(
+            store.join(STORE_INDEX),
+            "path,size,hash\n\
+             /a.txt,3,149603e6c03516362a8da23f624db945\n\
+             /a.txt,3,22af645d1859cb5ca6da0c484f1f37ea\n",
+        )
+        .unwrap();
+        let target_folder = dir.path().join("out");
+        let config = RestoreFilesConfig {
+            store,
+            target_folder: target_folder.clone(),
+            ..RestoreFilesConfig::default()
+        };
+        let mut ctx = Context::new(config).unwrap();
+        ctx.process().unwrap();
+        assert_eq!(ctx.lines_written, 1);
+        assert_eq!(ctx.copied_size, 3);
+        let restored = std::fs::read_to_string(target_folder.join("a.txt")).unwrap();
+        assert_eq!(restored, "new");
+    }
+
+    #[test]
+    fn test_paths_stay_inside_the_output_folder() {
+        let relative_path = |path: &str, strip_prefix: Option<&str>| {
+            let config = RestoreFilesConfig {
+                strip_prefix: strip_prefix.map(PathBuf::from),
+                ..RestoreFilesConfig::default()
+            };
+            let ctx = Context::new(config).unwrap();
+            let record = Record::for_test(path, 0, "NULL");
+            ctx.relative_path(&record)
+        };
+        assert_eq!(
+            relative_path("/data/a/./b.txt", None).unwrap(),
+            Path::new("data/a/b.txt")
+        );
+        assert_eq!(
+            relative_path("/data/a/b.txt", Some("/data")).unwrap(),
+            Path::new("a/b.txt")
+        );
+        assert!(relative_path("/data/../../etc/passwd", None).is_err());
+        assert!(relative_path("../etc/passwd", None).is_err());
+        assert!(relative_path("/data/../etc/passwd", Some("/data")).is_err());
+        assert!(relative_path("/data", Some("/data")).is_err());
+    }
+}
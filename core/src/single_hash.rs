@@ -1,7 +1,6 @@
-use crate::common::{Debug, HashAlgorithm};
+use crate::common::{Debug, HashAlgorithm, SizeFormat};
 use crate::internals::compute_hash;
 use anyhow::{anyhow, Result};
-use size_format::SizeFormatterSI;
 use std::fs::File;
 use std::path::PathBuf;
 use std::time::Instant;
@@ -13,6 +12,7 @@ pub struct SingleHashConfig {
     pub algorithm: HashAlgorithm,
     pub debug: Debug,
     pub error_log: Option<PathBuf>,
+    pub size_format: SizeFormat,
 }
 
 pub fn single_hash(config: SingleHashConfig) -> Result<()> {
@@ -22,7 +22,10 @@ pub fn single_hash(config: SingleHashConfig) -> Result<()> {
     let hash = ctx.process()?;
     println!("Calculated hash is: {}", hash);
     println!("Duration: {:#?}", (Instant::now() - now));
-    println!("File size: {}", SizeFormatterSI::new(ctx.file_size));
+    println!(
+        "File size: {}",
+        ctx.config.size_format.format(ctx.file_size)
+    );
     println!("Errors: {} ({:?})", 0, ctx.config.error_log);
     Ok(())
 }
@@ -1,8 +1,7 @@
-use crate::common::Debug;
+use crate::common::{Debug, SizeFormat};
 use crate::internals::Record;
 use anyhow::Result;
 use num_format::{Locale, ToFormattedString};
-use size_format::SizeFormatterSI;
 use std::collections::HashSet;
 use std::fs::File;
 use std::path::PathBuf;
@@ -16,6 +15,7 @@ pub struct UniquePathsConfig {
     pub only_paths: bool,
     pub debug: Debug,
     pub error_log: Option<PathBuf>,
+    pub size_format: SizeFormat,
 }
 
 pub fn unique_paths(config: UniquePathsConfig) -> Result<()> {
@@ -34,8 +34,8 @@ pub fn unique_paths(config: UniquePathsConfig) -> Result<()> {
         ctx.paths_discarded.to_formatted_string(&Locale::en)
     );
     println!(
-        "Size of all files: {}B",
-        SizeFormatterSI::new(ctx.total_size)
+        "Size of all files: {}",
+        ctx.config.size_format.format(ctx.total_size)
     );
     println!("Errors: {} ({:?})", 0, ctx.config.error_log);
     Ok(())
@@ -3,8 +3,9 @@ extern crate structopt;
 extern crate structopt_derive;
 
 use anyhow::Result;
-use core::common::Debug;
-use core::filter_paths::{filter_paths, FilterPath, FilterPathsConfig};
+use core::common::{ByteSize, Debug, FileCategory, GroupKey, SizeFormat, Timestamp};
+use core::filter_expr::FilterExpr;
+use core::filter_paths::{filter_paths, FilterPath, FilterPathsConfig, DEFAULT_SORT_BUFFER};
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -20,8 +21,11 @@ struct CliOpts {
     #[structopt(short = "o", long = "output", help = "Output file")]
     target_file: String,
 
-    #[structopt(long = "size-min", help = "Minimum size to consider (Default 0).")]
-    size_min: Option<u64>,
+    #[structopt(
+        long = "size-min",
+        help = "Minimum size to consider, like 500KB or 10MiB (Default 0)."
+    )]
+    size_min: Option<ByteSize>,
 
     #[structopt(short = "d", long = "debug", help = "Activates debug mode.")]
     debug: bool,
@@ -29,11 +33,17 @@ struct CliOpts {
     #[structopt(short = "e", long = "error-log", help = "Error log file.")]
     error_log: Option<String>,
 
+    #[structopt(
+        long = "size-format",
+        help = "Units used to print sizes: si (kB, MB, GB) or iec (KiB, MiB, GiB). Default is si."
+    )]
+    size_format: Option<SizeFormat>,
+
     #[structopt(
         long = "size-max",
-        help = "Maximum size to consider (Default u64 MAX)."
+        help = "Maximum size to consider, like 500KB or 10MiB (Default u64 MAX)."
     )]
-    size_max: Option<u64>,
+    size_max: Option<ByteSize>,
 
     #[structopt(
         long = "exclude-unique-sizes",
@@ -47,73 +57,137 @@ struct CliOpts {
     )]
     unique_hashes: bool,
 
+    #[structopt(
+        long = "exclude-unique-by",
+        help = "Exclude files that share none of these keys with other files in the set: size, hash, basename, basename-ci (ignoring case), stem (without extension nor ' - Copy (N)'), extension, size-basename."
+    )]
+    exclude_unique_by: Vec<GroupKey>,
+
+    #[structopt(
+        long = "exclude-duplicated-by",
+        help = "Exclude files that share any of these keys with other files in the set, same keys as --exclude-unique-by."
+    )]
+    exclude_duplicated_by: Vec<GroupKey>,
+
     #[structopt(
         long = "blacklist-path-starts",
-        help = "Excluding paths starting in this way. Prepend ':case-insensitive:!' if you don't wanna have a case sensitive match."
+        help = "Excluding paths starting in this way. Prefix with 'i:' for a case insensitive match, and 're:', 'glob:' or 'name:' for a regex, a glob or an exact file name."
     )]
-    blacklist_path_starts: Vec<String>,
+    blacklist_path_starts: Vec<FilterPath>,
 
     #[structopt(
         long = "blacklist-path-ends",
-        help = "Excluding paths ending in this way. Prepend ':case-insensitive:!' if you don't wanna have a case sensitive match."
+        help = "Excluding paths ending in this way. Prefix with 'i:' for a case insensitive match, and 're:', 'glob:' or 'name:' for a regex, a glob or an exact file name."
     )]
-    blacklist_path_ends: Vec<String>,
+    blacklist_path_ends: Vec<FilterPath>,
 
     #[structopt(
         long = "blacklist-path-containing",
-        help = "Excluding paths containing these substrings. Prepend ':case-insensitive:!' if you don't wanna have a case sensitive match."
+        help = "Excluding paths containing these substrings. Prefix with 'i:' for a case insensitive match, and 're:', 'glob:' or 'name:' for a regex, a glob or an exact file name."
     )]
-    blacklist_path_contents: Vec<String>,
+    blacklist_path_contents: Vec<FilterPath>,
 
     #[structopt(
         long = "whitelist-path-ends",
-        help = "Include only paths ending in this way. Prepend ':case-insensitive:!' if you don't wanna have a case sensitive match."
+        help = "Include only paths ending in this way. Prefix with 'i:' for a case insensitive match, and 're:', 'glob:' or 'name:' for a regex, a glob or an exact file name."
     )]
-    whitelist_path_ends: Vec<String>,
+    whitelist_path_ends: Vec<FilterPath>,
 
     #[structopt(
         long = "whitelist-path-containing",
-        help = "Include only paths containing these substrings. Prepend ':case-insensitive:!' if you don't wanna have a case sensitive match."
+        help = "Include only paths containing these substrings. Prefix with 'i:' for a case insensitive match, and 're:', 'glob:' or 'name:' for a regex, a glob or an exact file name."
+    )]
+    whitelist_path_contents: Vec<FilterPath>,
+
+    #[structopt(
+        long = "filter",
+        help = "Include only paths matching this expression, like: ext in [jpg, png] and size > 10KB and not path ~ \"/Temp/\". Fields: path, name, ext, hash, size, modified. Operators: = != < <= > >= ~ !~ in starts_with ends_with contains istarts_with iends_with icontains, combined with and, or, not and parentheses."
+    )]
+    expression: Option<FilterExpr>,
+
+    #[structopt(
+        long = "whitelist-file-types",
+        help = "Include only files whose content is of these types, no matter their extension: image, audio, video, document, archive. Reads the first bytes of each file."
+    )]
+    whitelist_file_types: Vec<FileCategory>,
+
+    #[structopt(
+        long = "category",
+        help = "Include only files with the extensions of these categories: pictures, raw-photos, music, video, documents, source-code, archives, disk-images, or the ones defined in --categories-file."
     )]
-    whitelist_path_contents: Vec<String>,
+    categories: Vec<String>,
+
+    #[structopt(
+        long = "categories-file",
+        help = "JSON file with more categories, like {\"comics\": [\"cbz\", \"cbr\"]}. They replace built-in categories with the same name."
+    )]
+    categories_file: Option<String>,
+
+    #[structopt(
+        long = "modified-after",
+        help = "Include only files modified at or after this local date, like 2019-06-15 or \"2019-06-15 18:30\", or this time ago, like 12h, 30d, 2w or 1y."
+    )]
+    modified_after: Option<Timestamp>,
+
+    #[structopt(
+        long = "modified-before",
+        help = "Include only files modified before this local date, like 2020-01-01, or this time ago, like 12h, 30d, 2w or 1y."
+    )]
+    modified_before: Option<Timestamp>,
+
+    #[structopt(
+        long = "sort-buffer",
+        help = "Records kept in memory while looking for unique or duplicated files, the rest are spilled to temporary files. Default value is 1000000."
+    )]
+    sort_buffer: Option<usize>,
+
+    #[structopt(
+        long = "temp-dir",
+        help = "Folder for the temporary files used to find unique or duplicated files. Default is the system one."
+    )]
+    temp_dir: Option<String>,
+
+    #[structopt(
+        long = "rejected",
+        help = "CSV file where excluded paths are written along with the rule that excluded them, like blacklist_path_ends[2], size_max or unique_size."
+    )]
+    rejected_file: Option<String>,
 }
 
 impl CliOpts {
     fn into_config(self) -> FilterPathsConfig {
+        let mut exclude_unique_by = Vec::new();
+        if self.unique_sizes {
+            exclude_unique_by.push(GroupKey::Size);
+        }
+        if self.unique_hashes {
+            exclude_unique_by.push(GroupKey::Hash);
+        }
+        exclude_unique_by.extend(self.exclude_unique_by);
         FilterPathsConfig {
             source_file: PathBuf::from(&self.source_file),
             target_file: PathBuf::from(&self.target_file),
             debug: if self.debug { Debug::On } else { Debug::Off },
             error_log: self.error_log.as_ref().map(|path| PathBuf::from(&path)),
-            size_min: self.size_min.unwrap_or(0),
-            size_max: self.size_max.unwrap_or(std::u64::MAX),
-            unique_sizes: self.unique_sizes,
-            unique_hashes: self.unique_hashes,
-            blacklist_path_starts: self
-                .blacklist_path_starts
-                .iter()
-                .map(|p| FilterPath::new(p))
-                .collect(),
-            blacklist_path_ends: self
-                .blacklist_path_ends
-                .iter()
-                .map(|p| FilterPath::new(p))
-                .collect(),
-            blacklist_path_contents: self
-                .blacklist_path_contents
-                .iter()
-                .map(|p| FilterPath::new(p))
-                .collect(),
-            whitelist_path_ends: self
-                .whitelist_path_ends
-                .iter()
-                .map(|p| FilterPath::new(p))
-                .collect(),
-            whitelist_path_contents: self
-                .whitelist_path_contents
-                .iter()
-                .map(|p| FilterPath::new(p))
-                .collect(),
+            size_format: self.size_format.unwrap_or_default(),
+            size_min: self.size_min.map_or(0, |size| size.0),
+            size_max: self.size_max.map_or(u64::MAX, |size| size.0),
+            exclude_unique_by,
+            exclude_duplicated_by: self.exclude_duplicated_by,
+            blacklist_path_starts: self.blacklist_path_starts,
+            blacklist_path_ends: self.blacklist_path_ends,
+            blacklist_path_contents: self.blacklist_path_contents,
+            whitelist_path_ends: self.whitelist_path_ends,
+            whitelist_path_contents: self.whitelist_path_contents,
+            expression: self.expression,
+            whitelist_file_types: self.whitelist_file_types,
+            categories: self.categories,
+            categories_file: self.categories_file.as_ref().map(PathBuf::from),
+            modified_after: self.modified_after.map(|date| date.0),
+            modified_before: self.modified_before.map(|date| date.0),
+            sort_buffer: self.sort_buffer.unwrap_or(DEFAULT_SORT_BUFFER),
+            temp_dir: self.temp_dir.as_ref().map(PathBuf::from),
+            rejected_file: self.rejected_file.as_ref().map(PathBuf::from),
         }
     }
 }
@@ -3,7 +3,7 @@ extern crate structopt;
 extern crate structopt_derive;
 
 use anyhow::Result;
-use core::common::{Debug, TraverseMode};
+use core::common::{Debug, SizeFormat, SortOrder, TraverseMode};
 use core::gather_paths::{gather_paths, GatherPathsConfig};
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -24,18 +24,76 @@ struct CliOpts {
     )]
     recursive: bool,
 
+    #[structopt(
+        long = "min-depth",
+        help = "Skip files above this depth. Files directly inside the input paths are at depth 1."
+    )]
+    min_depth: Option<usize>,
+
+    #[structopt(
+        long = "max-depth",
+        help = "Don't descend into folders beyond this depth. Files directly inside the input paths are at depth 1."
+    )]
+    max_depth: Option<usize>,
+
+    #[structopt(
+        short = "x",
+        long = "one-file-system",
+        help = "Don't descend into folders on other file systems than the input path. Only supported on unix."
+    )]
+    one_file_system: bool,
+
     #[structopt(
         short = "u",
         long = "unsorted",
-        help = "Takes the source paths as-is, without sorting them."
+        help = "Takes the source paths and folder entries as-is, without sorting them."
     )]
     unsorted: bool,
 
+    #[structopt(
+        short = "s",
+        long = "sort",
+        help = "Choose sort order for source paths and folder entries: bytewise, natural, case-insensitive. Default is bytewise."
+    )]
+    sort_order: Option<SortOrder>,
+
+    #[structopt(
+        short = "t",
+        long = "threads",
+        help = "Number of threads used to walk the directories. Default value is 0, one per CPU."
+    )]
+    threads: Option<usize>,
+
+    #[structopt(
+        long = "previous-snapshot",
+        help = "Output file of a previous run. Files in folders whose modification time didn't change are taken from it instead of being read again, so files rewritten in place within those folders are not detected."
+    )]
+    previous_snapshot: Option<String>,
+
+    #[structopt(
+        long = "only-deltas",
+        help = "Only output files that are new or changed since the previous snapshot. Deleted files and folders are not listed, compare full outputs to find them."
+    )]
+    only_deltas: bool,
+
+    #[structopt(
+        short = "a",
+        long = "scan-archives",
+        help = "Also gather files inside zip, tar, tar.gz and 7z archives and ISO 9660 disc images, with paths like '/backup/a.zip!/photos/img.jpg' and the in_archive column set."
+    )]
+    scan_archives: bool,
+
     #[structopt(short = "d", long = "debug", help = "Activates debug mode.")]
     debug: bool,
 
     #[structopt(short = "e", long = "error-log", help = "Error log file.")]
     error_log: Option<String>,
+
+    #[structopt(
+        long = "size-format",
+        help = "Units used to print sizes: si (kB, MB, GB) or iec (KiB, MiB, GiB). Default is si."
+    )]
+    size_format: Option<SizeFormat>,
 }
 
 impl CliOpts {
@@ -49,8 +107,17 @@ impl CliOpts {
                 TraverseMode::NonRecursive
             },
             unsorted: self.unsorted,
+            sort_order: self.sort_order.unwrap_or(SortOrder::ByteWise),
+            threads: self.threads.unwrap_or(0),
+            min_depth: self.min_depth.unwrap_or(0),
+            max_depth: self.max_depth,
+            one_file_system: self.one_file_system,
+            previous_snapshot: self.previous_snapshot.as_ref().map(PathBuf::from),
+            only_deltas: self.only_deltas,
+            scan_archives: self.scan_archives,
             debug: if self.debug { Debug::On } else { Debug::Off },
             error_log: self.error_log.as_ref().map(|path| PathBuf::from(&path)),
+            size_format: self.size_format.unwrap_or_default(),
         }
     }
 }
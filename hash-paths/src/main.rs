@@ -3,7 +3,7 @@ extern crate structopt;
 extern crate structopt_derive;
 
 use anyhow::Result;
-use core::common::{Debug, HashAlgorithm};
+use core::common::{ByteSize, Debug, HashAlgorithm};
 use core::hash_paths::{hash_paths, HashPathsConfig};
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -20,9 +20,9 @@ struct CliOpts {
     #[structopt(
         short = "b",
         long = "bytes",
-        help = "Determine how many bytes are readed to calculate the hash. Zero means all bytes. Default value is 0."
+        help = "Determine how many bytes are readed to calculate the hash, like 10000 or 64KiB. Zero means all bytes. Default value is 0."
     )]
-    bytes: Option<u64>,
+    bytes: Option<ByteSize>,
 
     #[structopt(
         short = "a",
@@ -50,7 +50,7 @@ impl CliOpts {
         HashPathsConfig {
             source_file: PathBuf::from(&self.source_file),
             target_file: PathBuf::from(&self.target_file),
-            bytes: self.bytes.unwrap_or(0),
+            bytes: self.bytes.map_or(0, |bytes| bytes.0),
             algorithm: self.algorithm.unwrap_or(HashAlgorithm::Md5),
             show_progression: self.progression,
             debug: if self.debug { Debug::On } else { Debug::Off },
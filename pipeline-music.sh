@@ -17,7 +17,7 @@ echo
 ./target/release/filter-paths \
     --input ${OUT}/all_paths.csv --output ${OUT}/all_pics_1.csv \
     --error-log ${OUT}/errors_all_pics_1.log \
-    --whitelist-path-ends ":case-insensitive:!.mp3" ":case-insensitive:!.ogg"
+    --category music
 echo
 ./target/release/filter-paths \
     --input ${OUT}/all_pics_1.csv --output ${OUT}/filtered_1.csv \
@@ -47,8 +47,8 @@ echo
     --error-log ${OUT}/error_unique.log
 echo
 ./target/release/copy-files \
-    --input ${OUT}/unique.csv --output ${OUT}/mp3/ \
+    --input ${OUT}/unique.csv --output ${OUT}/audio/ \
     --error-log ${OUT}/error_copy_files.log \
-    --flatten-output
+    --template "{extension}/{name}"
 echo
 echo "DONE!"
\ No newline at end of file
@@ -17,9 +17,7 @@ echo
 ./target/release/filter-paths \
     --input ${OUT}/all_paths.csv --output ${OUT}/all_pics_1.csv \
     --error-log ${OUT}/errors_all_pics_1.log \
-    --whitelist-path-ends ":case-insensitive:!.png" ":case-insensitive:!.jpg" ":case-insensitive:!.jpeg" \
-        ":case-insensitive:!.gif" ":case-insensitive:!.bmp" ":case-insensitive:!.raw" ":case-insensitive:!.webp" \
-        ":case-insensitive:!.tiff" ":case-insensitive:!.svg" \
+    --category pictures raw-photos \
     --whitelist-path-containing "/DSC" "/PIC" "/IMG" "/WhatsApp"
 echo
 ./target/release/filter-paths \
@@ -51,6 +49,9 @@ echo
 echo
 ./target/release/copy-files \
     --input ${OUT}/unique.csv --output ${OUT}/pics/ \
-    --error-log ${OUT}/error_copy_files.log
+    --error-log ${OUT}/error_copy_files.log \
+    --template "{year}/{month}/{name}" \
+    --date-source exif \
+    --no-exif-log ${OUT}/no_exif_copy_files.log
 echo
 echo "DONE!"
\ No newline at end of file
@@ -0,0 +1,87 @@
+extern crate structopt;
+#[macro_use]
+extern crate structopt_derive;
+
+use anyhow::Result;
+use core::common::{Debug, SizeFormat};
+use core::restore_files::{restore_files, RestoreFilesConfig};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "restore-files",
+    about = "Rebuilds the original folders of the files in a store made by copy-files --store."
+)]
+struct CliOpts {
+    #[structopt(short = "s", long = "store", help = "Store folder")]
+    store: String,
+
+    #[structopt(
+        short = "i",
+        long = "index",
+        help = "Index of the files to restore, like a filtered copy of the one in the store. Default is the whole store index."
+    )]
+    index_file: Option<String>,
+
+    #[structopt(short = "o", long = "output", help = "Output folder")]
+    target_folder: String,
+
+    #[structopt(
+        long = "strip-prefix",
+        help = "Remove this folder from the start of the original paths."
+    )]
+    strip_prefix: Option<String>,
+
+    #[structopt(
+        long = "hardlinks",
+        help = "Hardlink the files to the store instead of copying them, when it's in the same filesystem. Hardlinked files share the timestamps of their object in the store: the modification dates of the index are only restored on copies."
+    )]
+    hardlinks: bool,
+
+    #[structopt(
+        long = "verify",
+        help = "Check that the restored files have the hash written in the index."
+    )]
+    verify: bool,
+
+    #[structopt(
+        short = "p",
+        long = "show-progression",
+        help = "Show progression information."
+    )]
+    progression: bool,
+
+    #[structopt(short = "d", long = "debug", help = "Activates debug mode.")]
+    debug: bool,
+
+    #[structopt(short = "e", long = "error-log", help = "Error log file.")]
+    error_log: Option<String>,
+
+    #[structopt(
+        long = "size-format",
+        help = "Units used to print sizes: si (kB, MB, GB) or iec (KiB, MiB, GiB). Default is si."
+    )]
+    size_format: Option<SizeFormat>,
+}
+
+impl CliOpts {
+    fn into_config(self) -> RestoreFilesConfig {
+        RestoreFilesConfig {
+            store: PathBuf::from(&self.store),
+            index_file: self.index_file.as_ref().map(PathBuf::from),
+            target_folder: PathBuf::from(&self.target_folder),
+            strip_prefix: self.strip_prefix.as_ref().map(PathBuf::from),
+            hardlinks: self.hardlinks,
+            verify: self.verify,
+            show_progression: self.progression,
+            debug: if self.debug { Debug::On } else { Debug::Off },
+            error_log: self.error_log.as_ref().map(|path| PathBuf::from(&path)),
+            size_format: self.size_format.unwrap_or_default(),
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    restore_files(CliOpts::from_args().into_config())
+}
@@ -3,7 +3,7 @@ extern crate structopt;
 extern crate structopt_derive;
 
 use anyhow::Result;
-use core::common::{Debug, HashAlgorithm};
+use core::common::{ByteSize, Debug, HashAlgorithm, SizeFormat};
 use core::single_hash::{single_hash, SingleHashConfig};
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -20,9 +20,9 @@ struct CliOpts {
     #[structopt(
         short = "b",
         long = "bytes",
-        help = "Determine how many bytes are readed to calculate the hash. Zero means all bytes. Default value is 0."
+        help = "Determine how many bytes are readed to calculate the hash, like 10000 or 64KiB. Zero means all bytes. Default value is 0."
     )]
-    bytes: Option<u64>,
+    bytes: Option<ByteSize>,
 
     #[structopt(
         short = "a",
@@ -33,6 +33,12 @@ struct CliOpts {
 
     #[structopt(short = "e", long = "error-log", help = "Error log file.")]
     error_log: Option<String>,
+
+    #[structopt(
+        long = "size-format",
+        help = "Units used to print sizes: si (kB, MB, GB) or iec (KiB, MiB, GiB). Default is si."
+    )]
+    size_format: Option<SizeFormat>,
 }
 
 impl CliOpts {
@@ -41,7 +47,7 @@ impl CliOpts {
             source_file: PathBuf::from(&self.source_file),
             debug: if self.debug { Debug::On } else { Debug::Off },
             bytes: if let Some(bytes) = self.bytes {
-                bytes
+                bytes.0
             } else {
                 0
             },
@@ -51,6 +57,7 @@ impl CliOpts {
                 HashAlgorithm::Md5
             },
             error_log: self.error_log.as_ref().map(|path| PathBuf::from(&path)),
+            size_format: self.size_format.unwrap_or_default(),
         }
     }
 }
@@ -3,7 +3,7 @@ extern crate structopt;
 extern crate structopt_derive;
 
 use anyhow::Result;
-use core::common::Debug;
+use core::common::{Debug, SizeFormat};
 use core::unique_paths::{unique_paths, UniquePathsConfig};
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -35,6 +35,12 @@ struct CliOpts {
 
     #[structopt(short = "e", long = "error-log", help = "Error log file.")]
     error_log: Option<String>,
+
+    #[structopt(
+        long = "size-format",
+        help = "Units used to print sizes: si (kB, MB, GB) or iec (KiB, MiB, GiB). Default is si."
+    )]
+    size_format: Option<SizeFormat>,
 }
 
 impl CliOpts {
@@ -46,6 +52,7 @@ impl CliOpts {
             only_paths: self.only_paths,
             debug: if self.debug { Debug::On } else { Debug::Off },
             error_log: self.error_log.as_ref().map(|path| PathBuf::from(&path)),
+            size_format: self.size_format.unwrap_or_default(),
         }
     }
 }